@@ -0,0 +1,122 @@
+//! Chainable configuration of a [Converter].
+
+use crate::{Converter, ConverterError, ConverterOptions};
+use plist::Value;
+
+/// Configures a [Converter] before the archive is read, see
+/// [Converter::builder].
+///
+/// ```rust,no_run
+/// use nskeyedarchiver_converter::Converter;
+///
+/// let converter = Converter::builder()
+///     .treat_all_as_classes(true)
+///     .leave_null_values(true)
+///     .from_file("./archive.plist")?;
+/// let decoded = converter.decode_to_dictionary()?;
+/// # Ok::<(), nskeyedarchiver_converter::ConverterError>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConverterBuilder {
+    options: ConverterOptions,
+}
+
+impl Converter {
+    /// Creates a [ConverterBuilder] with the default options.
+    pub fn builder() -> ConverterBuilder {
+        ConverterBuilder::default()
+    }
+}
+
+impl ConverterBuilder {
+    /// Replaces all options, including the ones set on this builder so far.
+    pub fn options(mut self, options: ConverterOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// See [ConverterOptions::treat_all_as_classes].
+    pub fn treat_all_as_classes(mut self, value: bool) -> Self {
+        self.options.treat_all_as_classes = value;
+        self
+    }
+
+    /// See [ConverterOptions::leave_null_values].
+    pub fn leave_null_values(mut self, value: bool) -> Self {
+        self.options.leave_null_values = value;
+        self
+    }
+
+    /// Creates a converter for a [plist::Value], see
+    /// [Converter::new_with_options].
+    pub fn build(self, plist: Value) -> Result<Converter, ConverterError> {
+        Converter::new_with_options(plist, self.options)
+    }
+
+    /// Reads a plist file and creates a converter for it, see
+    /// [Converter::from_file_with_options].
+    pub fn from_file<P: AsRef<std::path::Path>>(
+        self,
+        path: P,
+    ) -> Result<Converter, ConverterError> {
+        Converter::from_file_with_options(path, self.options)
+    }
+
+    /// Reads a plist from a byte slice and creates a converter for it, see
+    /// [Converter::from_bytes_with_options].
+    pub fn from_bytes(self, bytes: &[u8]) -> Result<Converter, ConverterError> {
+        Converter::from_bytes_with_options(bytes, self.options)
+    }
+
+    /// Reads a plist from a seekable byte stream and creates a converter for
+    /// it, see [Converter::from_reader_with_options].
+    pub fn from_reader<R: std::io::Read + std::io::Seek>(
+        self,
+        reader: R,
+    ) -> Result<Converter, ConverterError> {
+        Converter::from_reader_with_options(reader, self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::*;
+    use crate::Converter;
+    use plist::Value;
+
+    #[test]
+    fn builder_sets_both_flags() {
+        let plist = archive(
+            vec![("root", uid(1))],
+            vec![
+                string("$null"),
+                array(2, &[0, 3]),
+                class(&["NSArray", "NSObject"]),
+                int(1),
+            ],
+        );
+        let mut bytes = Vec::new();
+        plist::to_writer_binary(&mut bytes, &plist).unwrap();
+
+        let built = Converter::builder()
+            .treat_all_as_classes(true)
+            .leave_null_values(true)
+            .build(plist)
+            .unwrap();
+        let from_bytes = Converter::builder()
+            .treat_all_as_classes(true)
+            .leave_null_values(true)
+            .from_bytes(&bytes)
+            .unwrap();
+        for converter in [built, from_bytes] {
+            assert!(converter.treat_all_as_classes());
+            assert!(converter.leave_null_values());
+            let decoded = decode_root(&converter).into_dictionary().unwrap();
+            assert_eq!(
+                decoded["NS.objects"],
+                Value::Array(vec![string("$null"), int(1)])
+            );
+            assert!(decoded.contains_key("$classes"));
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Structural comparison of decoded values.
+
+use crate::with_stack_guard;
+use plist::Value;
+
+/// A difference between two values found by [diff].
+///
+/// Paths are made of dictionary keys separated by dots and array indices in
+/// brackets, e.g. `root.items[2].title`. The path of the compared values
+/// themselves is empty.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+    /// A value is only present in the second value.
+    Added { path: String, value: Value },
+    /// A value is only present in the first value.
+    Removed { path: String, value: Value },
+    /// A value is present in both values but isn't equal.
+    Changed {
+        path: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+impl Difference {
+    /// Returns the path of the differing value.
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Added { path, .. } | Self::Removed { path, .. } | Self::Changed { path, .. } => {
+                path
+            }
+        }
+    }
+}
+
+/// Compares two decoded values, e.g. outputs of [crate::Converter::decode],
+/// and returns the differences between them.
+///
+/// Dictionaries are compared key by key and arrays are compared element by
+/// element, so an element inserted in the middle of an array shows up as a
+/// change of every following element.
+///
+/// ```rust
+/// use nskeyedarchiver_converter::{diff, plist::{Dictionary, Value}, Difference};
+///
+/// let archive = |title: &str| {
+///     let mut item = Dictionary::new();
+///     item.insert("title".to_string(), Value::String(title.to_string()));
+///     let mut root = Dictionary::new();
+///     root.insert("items".to_string(), Value::Array(vec![Value::Dictionary(item)]));
+///     Value::Dictionary(root)
+/// };
+///
+/// let differences = diff(&archive("Foo"), &archive("Bar"));
+/// assert_eq!(
+///     differences,
+///     vec![Difference::Changed {
+///         path: "items[0].title".to_string(),
+///         old: Value::String("Foo".to_string()),
+///         new: Value::String("Bar".to_string()),
+///     }]
+/// );
+/// assert!(diff(&archive("Foo"), &archive("Foo")).is_empty());
+/// ```
+pub fn diff(a: &Value, b: &Value) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    diff_values(String::new(), a, b, &mut differences);
+    differences
+}
+
+fn diff_values(path: String, a: &Value, b: &Value, differences: &mut Vec<Difference>) {
+    with_stack_guard(|| diff_values_unguarded(path, a, b, differences))
+}
+
+fn diff_values_unguarded(path: String, a: &Value, b: &Value, differences: &mut Vec<Difference>) {
+    match (a, b) {
+        (Value::Dictionary(a), Value::Dictionary(b)) => {
+            for (key, a_value) in a {
+                let key_path = key_path(&path, key);
+                match b.get(key) {
+                    Some(b_value) => diff_values(key_path, a_value, b_value, differences),
+                    None => differences.push(Difference::Removed {
+                        path: key_path,
+                        value: a_value.clone(),
+                    }),
+                }
+            }
+            for (key, b_value) in b {
+                if !a.contains_key(key) {
+                    differences.push(Difference::Added {
+                        path: key_path(&path, key),
+                        value: b_value.clone(),
+                    });
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for (index, a_value) in a.iter().enumerate() {
+                let index_path = format!("{path}[{index}]");
+                match b.get(index) {
+                    Some(b_value) => diff_values(index_path, a_value, b_value, differences),
+                    None => differences.push(Difference::Removed {
+                        path: index_path,
+                        value: a_value.clone(),
+                    }),
+                }
+            }
+            for (index, b_value) in b.iter().enumerate().skip(a.len()) {
+                differences.push(Difference::Added {
+                    path: format!("{path}[{index}]"),
+                    value: b_value.clone(),
+                });
+            }
+        }
+        _ if a != b => differences.push(Difference::Changed {
+            path,
+            old: a.clone(),
+            new: b.clone(),
+        }),
+        _ => {}
+    }
+}
+
+fn key_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    fn decoded_person(age: i64, nickname: Option<&str>) -> Value {
+        let mut objects = vec![
+            string("$null"),
+            object(2, vec![("address", uid(3))]),
+            class(&["Person", "NSObject"]),
+            object(4, vec![("age", int(age))]),
+            class(&["Address", "NSObject"]),
+        ];
+        if let Some(nickname) = nickname {
+            objects[1] = object(2, vec![("address", uid(3)), ("nickname", uid(5))]);
+            objects.push(string(nickname));
+        }
+        converter(objects).decode().unwrap()
+    }
+
+    #[test]
+    fn differences_of_decoded_archives_have_nested_paths() {
+        assert_eq!(
+            diff(&decoded_person(30, None), &decoded_person(31, Some("Al"))),
+            vec![
+                Difference::Changed {
+                    path: "root.address.age".to_string(),
+                    old: int(30),
+                    new: int(31),
+                },
+                Difference::Added {
+                    path: "root.nickname".to_string(),
+                    value: string("Al"),
+                },
+            ]
+        );
+        assert!(diff(&decoded_person(30, None), &decoded_person(30, None)).is_empty());
+    }
+}
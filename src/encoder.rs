@@ -0,0 +1,218 @@
+//! Encoding of decoded values back into NSKeyedArchiver plists.
+
+use crate::foundation::{date_to_cocoa_seconds, NS_TIME_KEY};
+use crate::{
+    with_stack_guard, Converter, ConverterError, ARCHIVER, ARCHIVER_KEY_NAME, ARCHIVER_VERSION,
+    MUTABLE_KEY_NAME, NULL_OBJECT_REFERENCE_NAME, OBJECTS_KEY_NAME, TOP_KEY_NAME, VERSION_KEY_NAME,
+};
+use plist::{Dictionary, Uid, Value};
+use std::collections::HashMap;
+
+/// Encodes a [plist::Value] in the format produced by [Converter::decode]
+/// back into a NSKeyedArchiver plist.
+///
+/// Values are mapped as follows:
+/// - a dictionary with a `$classes` key becomes an object of that class;
+/// - an array of `key`/`value` pairs and any other dictionary become a
+///   `NSDictionary`;
+/// - any other array becomes a `NSArray`;
+/// - a date becomes a `NSDate`;
+/// - a `$null` string becomes a reference to `$null`.
+///
+/// The encoding isn't guaranteed to be identical to the original archive,
+/// but it decodes into the same value.
+pub struct Encoder {
+    value: Value,
+}
+
+/// State that is carried through a single encoding pass.
+struct EncodeState {
+    objects: Vec<Value>,
+    /// Class objects keyed by their class chain.
+    classes: HashMap<Vec<String>, Uid>,
+}
+
+impl Converter {
+    /// Creates an [Encoder] for a decoded value, which allows to decode an
+    /// archive, modify it and encode it again.
+    ///
+    /// ```rust,no_run
+    /// use nskeyedarchiver_converter::{plist::Value, Converter};
+    ///
+    /// let mut decoded = Converter::from_file("foo.bin")?.decode()?;
+    /// if let Some(root) = decoded.as_dictionary_mut() {
+    ///     root.insert("title".to_string(), Value::String("Bar".to_string()));
+    /// }
+    /// let archive = Converter::to_encoder(decoded).encode()?;
+    /// archive.to_file_binary("foo.bin")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn to_encoder(value: Value) -> Encoder {
+        Encoder::new(value)
+    }
+}
+
+impl Encoder {
+    /// Creates a new encoder for a decoded value. It should be a dictionary
+    /// of top level keys.
+    pub fn new(value: Value) -> Self {
+        Self { value }
+    }
+
+    /// Encodes the value into a NSKeyedArchiver plist.
+    pub fn encode(&self) -> Result<Value, ConverterError> {
+        let Some(top_values) = self.value.as_dictionary() else {
+            return Err(ConverterError::WrongValueType("root", "Dictionary"));
+        };
+
+        let mut state = EncodeState {
+            objects: vec![Value::String(NULL_OBJECT_REFERENCE_NAME.to_string())],
+            classes: HashMap::new(),
+        };
+        let mut top = Dictionary::new();
+        for (key, value) in top_values {
+            top.insert(key.clone(), Value::Uid(state.encode_object(value)));
+        }
+
+        let mut archive = Dictionary::new();
+        archive.insert(
+            VERSION_KEY_NAME.to_string(),
+            Value::Integer(ARCHIVER_VERSION.into()),
+        );
+        archive.insert(
+            ARCHIVER_KEY_NAME.to_string(),
+            Value::String(ARCHIVER.to_string()),
+        );
+        archive.insert(TOP_KEY_NAME.to_string(), Value::Dictionary(top));
+        archive.insert(OBJECTS_KEY_NAME.to_string(), Value::Array(state.objects));
+        Ok(Value::Dictionary(archive))
+    }
+}
+
+impl EncodeState {
+    /// Appends an object to `$objects` and returns a reference to it.
+    fn encode_object(&mut self, value: &Value) -> Uid {
+        with_stack_guard(|| self.encode_object_unguarded(value))
+    }
+
+    fn encode_object_unguarded(&mut self, value: &Value) -> Uid {
+        if value.as_string() == Some(NULL_OBJECT_REFERENCE_NAME) {
+            return Uid::new(0);
+        }
+
+        // Reserve a slot first, so objects are stored before their members
+        let uid = Uid::new(self.objects.len() as u64);
+        self.objects.push(Value::Boolean(false));
+
+        let encoded = match value {
+            Value::Dictionary(dict) if dict.contains_key("$classes") => self.encode_class(dict),
+            Value::Dictionary(dict) => {
+                let pairs = dict
+                    .iter()
+                    .map(|(key, value)| (Value::String(key.clone()), value))
+                    .collect();
+                self.encode_dict(pairs)
+            }
+            Value::Array(array) if !array.is_empty() && array.iter().all(is_pair) => {
+                let pairs = array
+                    .iter()
+                    .filter_map(|pair| pair.as_dictionary())
+                    .map(|pair| (pair["key"].clone(), &pair["value"]))
+                    .collect();
+                self.encode_dict(pairs)
+            }
+            Value::Array(array) => {
+                let mut object = self.class_object(&["NSArray", "NSObject"]);
+                let references = array
+                    .iter()
+                    .map(|element| Value::Uid(self.encode_object(element)))
+                    .collect();
+                object.insert("NS.objects".to_string(), Value::Array(references));
+                Value::Dictionary(object)
+            }
+            Value::Date(date) => {
+                let mut object = self.class_object(&["NSDate", "NSObject"]);
+                object.insert(
+                    NS_TIME_KEY.to_string(),
+                    Value::Real(date_to_cocoa_seconds(*date)),
+                );
+                Value::Dictionary(object)
+            }
+            value => value.clone(),
+        };
+        self.objects[uid.get() as usize] = encoded;
+        uid
+    }
+
+    fn encode_dict(&mut self, pairs: Vec<(Value, &Value)>) -> Value {
+        let mut object = self.class_object(&["NSDictionary", "NSObject"]);
+        let mut keys = Vec::with_capacity(pairs.len());
+        let mut values = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            keys.push(Value::Uid(self.encode_object(&key)));
+            values.push(Value::Uid(self.encode_object(value)));
+        }
+        object.insert("NS.keys".to_string(), Value::Array(keys));
+        object.insert("NS.objects".to_string(), Value::Array(values));
+        Value::Dictionary(object)
+    }
+
+    /// Encodes an object of a custom class. Numbers and booleans are stored
+    /// inline, other values are stored as references.
+    fn encode_class(&mut self, dict: &Dictionary) -> Value {
+        let names: Vec<&str> = dict["$classes"]
+            .as_array()
+            .map(|classes| classes.iter().filter_map(Value::as_string).collect())
+            .unwrap_or_default();
+        let mut object = self.class_object(&names);
+        for (key, value) in dict {
+            let encoded = match value {
+                _ if key == "$classes" || key == MUTABLE_KEY_NAME => continue,
+                Value::Integer(_) | Value::Real(_) | Value::Boolean(_) => value.clone(),
+                Value::Array(array) => Value::Array(
+                    array
+                        .iter()
+                        .map(|element| Value::Uid(self.encode_object(element)))
+                        .collect(),
+                ),
+                value => Value::Uid(self.encode_object(value)),
+            };
+            object.insert(key.clone(), encoded);
+        }
+        Value::Dictionary(object)
+    }
+
+    /// Creates an object dictionary with a `$class` reference. Class objects
+    /// are shared between objects of the same class.
+    fn class_object(&mut self, names: &[&str]) -> Dictionary {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let uid = match self.classes.get(&names) {
+            Some(uid) => *uid,
+            None => {
+                let mut class = Dictionary::new();
+                if let Some(name) = names.first() {
+                    class.insert("$classname".to_string(), Value::String(name.clone()));
+                }
+                class.insert(
+                    "$classes".to_string(),
+                    Value::Array(names.iter().cloned().map(Value::String).collect()),
+                );
+                let uid = Uid::new(self.objects.len() as u64);
+                self.objects.push(Value::Dictionary(class));
+                self.classes.insert(names, uid);
+                uid
+            }
+        };
+
+        let mut object = Dictionary::new();
+        object.insert("$class".to_string(), Value::Uid(uid));
+        object
+    }
+}
+
+/// Checks if a value is a dictionary decoded into a `key`/`value` pair.
+fn is_pair(value: &Value) -> bool {
+    value.as_dictionary().is_some_and(|pair| {
+        pair.len() == 2 && pair.contains_key("key") && pair.contains_key("value")
+    })
+}
@@ -0,0 +1,106 @@
+//! Builders of hand-made archives for tests.
+
+use crate::{Converter, ConverterOptions};
+use plist::{Dictionary, Uid, Value};
+
+pub(crate) fn uid(index: u64) -> Value {
+    Value::Uid(Uid::new(index))
+}
+
+pub(crate) fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+pub(crate) fn int(n: i64) -> Value {
+    Value::Integer(n.into())
+}
+
+pub(crate) fn dict(pairs: Vec<(&str, Value)>) -> Value {
+    let mut dict = Dictionary::new();
+    for (key, value) in pairs {
+        dict.insert(key.to_string(), value);
+    }
+    Value::Dictionary(dict)
+}
+
+/// A class object with the given class chain.
+pub(crate) fn class(names: &[&str]) -> Value {
+    dict(vec![
+        ("$classname", string(names[0])),
+        (
+            "$classes",
+            Value::Array(names.iter().map(|name| string(name)).collect()),
+        ),
+    ])
+}
+
+/// An object of the class at `class_index` with the given fields.
+pub(crate) fn object(class_index: u64, mut fields: Vec<(&str, Value)>) -> Value {
+    fields.insert(0, ("$class", uid(class_index)));
+    dict(fields)
+}
+
+/// A `NSArray` object of the class at `class_index`.
+pub(crate) fn array(class_index: u64, elements: &[u64]) -> Value {
+    object(class_index, vec![("NS.objects", references(elements))])
+}
+
+/// A `NSDictionary` object of the class at `class_index`.
+pub(crate) fn dictionary(class_index: u64, keys: &[u64], values: &[u64]) -> Value {
+    object(
+        class_index,
+        vec![
+            ("NS.keys", references(keys)),
+            ("NS.objects", references(values)),
+        ],
+    )
+}
+
+pub(crate) fn references(indices: &[u64]) -> Value {
+    Value::Array(indices.iter().map(|&index| uid(index)).collect())
+}
+
+/// A NSKeyedArchiver plist. `objects` should start with `$null`.
+pub(crate) fn archive(top: Vec<(&str, Value)>, objects: Vec<Value>) -> Value {
+    dict(vec![
+        ("$version", int(100000)),
+        ("$archiver", string("NSKeyedArchiver")),
+        ("$top", dict(top)),
+        ("$objects", Value::Array(objects)),
+    ])
+}
+
+/// A converter for an archive with a single `root` key referencing the
+/// object at index 1.
+pub(crate) fn converter(objects: Vec<Value>) -> Converter {
+    Converter::new(archive(vec![("root", uid(1))], objects)).unwrap()
+}
+
+/// Same as [converter], but with the given options.
+pub(crate) fn converter_with_options(objects: Vec<Value>, options: ConverterOptions) -> Converter {
+    Converter::new_with_options(archive(vec![("root", uid(1))], objects), options).unwrap()
+}
+
+/// Decodes an archive created by [converter] and returns its root.
+pub(crate) fn decode_root(converter: &Converter) -> Value {
+    converter
+        .decode()
+        .unwrap()
+        .into_dictionary()
+        .unwrap()
+        .remove("root")
+        .unwrap()
+}
+
+/// Objects of `depth` dictionaries, each one holding the next one under
+/// a `next` key.
+pub(crate) fn nested_dictionaries(depth: u64) -> Vec<Value> {
+    let class_index = depth + 1;
+    let key_index = depth + 2;
+    let mut objects = vec![string("$null")];
+    objects.extend((1..=depth).map(|i| dictionary(class_index, &[key_index], &[i + 1])));
+    objects[depth as usize] = dictionary(class_index, &[key_index], &[key_index]);
+    objects.push(class(&["NSDictionary", "NSObject"]));
+    objects.push(string("next"));
+    objects
+}
@@ -0,0 +1,602 @@
+//! Conversions of Foundation values.
+
+use crate::{Converter, ConverterError, DecodeState, NULL_OBJECT_REFERENCE_NAME};
+use plist::{Date, Dictionary, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Key that holds the seconds since the reference date of a `NSDate`.
+pub(crate) const NS_TIME_KEY: &str = "NS.time";
+
+/// Key that holds the bytes of a `NSData`.
+pub(crate) const NS_DATA_KEY: &str = "NS.data";
+
+/// Key that holds the value of a `NSNumber` stored as an object.
+const NS_NUMBER_KEY: &str = "NS.number";
+
+/// Key that holds the base URL of a `NSURL`, which is often null.
+const NS_URL_BASE_KEY: &str = "NS.base";
+/// Key that holds the string of a `NSURL`, relative to its base if any.
+const NS_URL_RELATIVE_KEY: &str = "NS.relative";
+
+/// Key that holds the text of a `NSAttributedString`.
+const ATTRIBUTED_STRING_TEXT_KEY: &str = "NSString";
+/// Key that holds the attributes of a `NSAttributedString`. It's a single
+/// dictionary, or an array of dictionaries if there are several runs.
+const ATTRIBUTED_STRING_ATTRIBUTES_KEY: &str = "NSAttributes";
+
+/// Keys of the fields of a `NSDecimalNumber`.
+const DECIMAL_MANTISSA_KEY: &str = "NS.mantissa";
+const DECIMAL_BYTE_ORDER_KEY: &str = "NS.mantissa.bo";
+const DECIMAL_EXPONENT_KEY: &str = "NS.exponent";
+const DECIMAL_LENGTH_KEY: &str = "NS.length";
+const DECIMAL_NEGATIVE_KEY: &str = "NS.negative";
+
+/// Key that holds the 16 bytes of a `NSUUID`.
+const NS_UUID_BYTES_KEY: &str = "NS.uuidbytes";
+/// Lengths of the dash-separated groups of a UUID string in bytes.
+const UUID_GROUP_LENGTHS: [usize; 5] = [4, 2, 2, 2, 6];
+
+/// Key that holds the matrix of a `NSAffineTransform` as raw bytes.
+const TRANSFORM_STRUCT_KEY: &str = "NSTransformStruct";
+/// Names of the `NSAffineTransformStruct` fields in the order they're stored.
+const TRANSFORM_FIELDS: [&str; 6] = ["m11", "m12", "m21", "m22", "tX", "tY"];
+
+/// Seconds between the Unix epoch and the Cocoa reference date, 2001-01-01.
+const COCOA_EPOCH_OFFSET: f64 = 978_307_200.0;
+
+/// Converts seconds since the Cocoa reference date into a date. Returns `None`
+/// if the value is out of range.
+pub(crate) fn cocoa_seconds_to_date(seconds: f64) -> Option<Date> {
+    let unix_seconds = seconds + COCOA_EPOCH_OFFSET;
+    let time = if unix_seconds >= 0.0 {
+        UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(unix_seconds).ok()?)
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::try_from_secs_f64(-unix_seconds).ok()?)
+    }?;
+    Some(time.into())
+}
+
+/// Converts a date into seconds since the Cocoa reference date.
+pub(crate) fn date_to_cocoa_seconds(date: Date) -> f64 {
+    let unix_seconds = match SystemTime::from(date).duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    };
+    unix_seconds - COCOA_EPOCH_OFFSET
+}
+
+/// Reads seconds from a real or an integer value.
+pub(crate) fn as_seconds(value: &Value) -> Option<f64> {
+    match value {
+        Value::Real(seconds) => Some(*seconds),
+        Value::Integer(seconds) => seconds.as_signed().map(|seconds| seconds as f64),
+        _ => None,
+    }
+}
+
+impl Converter {
+    /// Decodes a `NSDate` into a date, see [Converter::date_from_seconds].
+    ///
+    /// Returns `None` if the time is missing or out of range, so the caller
+    /// can fall back to decoding it as a regular class.
+    pub(crate) fn decode_date(&self, dict: &Dictionary) -> Option<Value> {
+        let seconds = dict
+            .get(NS_TIME_KEY)
+            .and_then(|value| self.resolve_raw(value))
+            .and_then(as_seconds)?;
+        self.date_from_seconds(seconds)
+    }
+
+    /// Converts seconds since the Cocoa reference date into a date, an
+    /// ISO 8601 string with [crate::ConverterOptions::dates_as_iso_strings] or
+    /// raw seconds with [crate::ConverterOptions::dates_as_raw_seconds].
+    /// Returns `None` if the date is out of range.
+    pub(crate) fn date_from_seconds(&self, seconds: f64) -> Option<Value> {
+        if self.options.dates_as_raw_seconds {
+            return Some(Value::Real(seconds));
+        }
+        let date = cocoa_seconds_to_date(seconds)?;
+        match self.options.dates_as_iso_strings {
+            true => Some(Value::String(date.to_xml_format())),
+            false => Some(Value::Date(date)),
+        }
+    }
+
+    /// Decodes a `NSData` into its bytes.
+    ///
+    /// Returns `None` if the bytes are missing, so the caller can fall back to
+    /// decoding it as a regular class.
+    pub(crate) fn decode_data(&self, dict: &Dictionary) -> Option<Value> {
+        dict.get(NS_DATA_KEY)
+            .and_then(|value| self.resolve_raw(value))
+            .filter(|value| value.as_data().is_some())
+            .cloned()
+    }
+
+    /// Decodes a `NSNumber` stored as an object into its value. Numbers are
+    /// usually stored as plain values instead.
+    ///
+    /// Returns `None` if the value is missing or isn't a number, so the caller
+    /// can fall back to decoding it as a regular class.
+    pub(crate) fn decode_number(&self, dict: &Dictionary) -> Option<Value> {
+        dict.get(NS_NUMBER_KEY)
+            .and_then(|value| self.resolve_raw(value))
+            .filter(|value| {
+                matches!(
+                    value,
+                    Value::Integer(_) | Value::Real(_) | Value::Boolean(_)
+                )
+            })
+            .cloned()
+    }
+
+    /// Decodes a `NSURL` into an absolute URL string. A relative URL is
+    /// resolved against its base.
+    ///
+    /// Returns `None` if the URL string or the base is missing or malformed,
+    /// so the caller can fall back to decoding it as a regular class.
+    pub(crate) fn decode_url(
+        &self,
+        dict: &Dictionary,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        let Some(Value::String(relative)) = self.decode_field(dict, NS_URL_RELATIVE_KEY, state)?
+        else {
+            return Ok(None);
+        };
+        // The base is a `NSURL` too, so it's decoded into a string already
+        match self.decode_field(dict, NS_URL_BASE_KEY, state)? {
+            None => Ok(Some(Value::String(relative))),
+            Some(Value::String(base)) if base != NULL_OBJECT_REFERENCE_NAME => {
+                Ok(Some(Value::String(join_url(&base, &relative))))
+            }
+            Some(Value::String(_)) => Ok(Some(Value::String(relative))),
+            Some(_) => Ok(None),
+        }
+    }
+
+    /// Decodes a `NSAttributedString` into a `{string, attributes}`
+    /// dictionary. The ranges of the attribute runs aren't decoded.
+    ///
+    /// Returns `None` if the text is missing, so the caller can fall back to
+    /// decoding it as a regular class.
+    pub(crate) fn decode_attributed_string(
+        &self,
+        dict: &Dictionary,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        let Some(text @ Value::String(_)) =
+            self.decode_field(dict, ATTRIBUTED_STRING_TEXT_KEY, state)?
+        else {
+            return Ok(None);
+        };
+
+        let mut result = Dictionary::new();
+        result.insert("string".to_string(), text);
+        if let Some(attributes) =
+            self.decode_field(dict, ATTRIBUTED_STRING_ATTRIBUTES_KEY, state)?
+        {
+            result.insert("attributes".to_string(), attributes);
+        }
+        Ok(Some(Value::Dictionary(result)))
+    }
+
+    /// Decodes a `NSDecimalNumber` into a string in decimal notation, e.g.
+    /// `-123.45`, so no precision is lost. A number that isn't a number is
+    /// decoded into `NaN`.
+    ///
+    /// The mantissa is stored as up to eight 16-bit words, the least
+    /// significant first. Returns `None` if it's malformed, so the caller can
+    /// fall back to decoding it as a regular class.
+    pub(crate) fn decode_decimal_number(&self, dict: &Dictionary) -> Option<Value> {
+        let field = |key| dict.get(key).and_then(|value| self.resolve_raw(value));
+        let bytes = field(DECIMAL_MANTISSA_KEY)?.as_data()?;
+        let exponent = field(DECIMAL_EXPONENT_KEY)?.as_signed_integer()?;
+        let length = field(DECIMAL_LENGTH_KEY)?.as_unsigned_integer()?;
+        let is_negative = field(DECIMAL_NEGATIVE_KEY)
+            .and_then(|value| match value {
+                Value::Boolean(b) => Some(*b),
+                value => value.as_unsigned_integer().map(|n| n != 0),
+            })
+            .unwrap_or(false);
+        let is_little_endian = field(DECIMAL_BYTE_ORDER_KEY)
+            .and_then(Value::as_unsigned_integer)
+            .is_none_or(|byte_order| byte_order == 1);
+
+        let length = usize::try_from(length).ok()?;
+        if length == 0 {
+            // A zero length with the sign set marks a NaN
+            let s = if is_negative { "NaN" } else { "0" };
+            return Some(Value::String(s.to_string()));
+        }
+        let words = bytes.get(..length.checked_mul(2)?)?;
+        let mut mantissa: u128 = 0;
+        for word in words.chunks_exact(2).rev() {
+            let word = match is_little_endian {
+                true => u16::from_le_bytes([word[0], word[1]]),
+                false => u16::from_be_bytes([word[0], word[1]]),
+            };
+            mantissa = mantissa.checked_mul(1 << 16)? | u128::from(word);
+        }
+        decimal_string(mantissa, exponent, is_negative).map(Value::String)
+    }
+
+    /// Decodes a `NSUUID` into a lowercase string like
+    /// `123e4567-e89b-12d3-a456-426614174000`.
+    pub(crate) fn decode_uuid(
+        &self,
+        object_ref: u64,
+        dict: &Dictionary,
+    ) -> Result<Value, ConverterError> {
+        let Some(bytes) = dict
+            .get(NS_UUID_BYTES_KEY)
+            .and_then(|value| self.resolve_raw(value))
+            .and_then(|value| value.as_data())
+            .filter(|bytes| bytes.len() == 16)
+        else {
+            return Err(ConverterError::invalid_encoding(object_ref));
+        };
+
+        let mut groups = Vec::with_capacity(UUID_GROUP_LENGTHS.len());
+        let mut rest = bytes;
+        for length in UUID_GROUP_LENGTHS {
+            let (group, tail) = rest.split_at(length);
+            groups.push(
+                group
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>(),
+            );
+            rest = tail;
+        }
+        Ok(Value::String(groups.join("-")))
+    }
+
+    /// Decodes a `NSAffineTransform` into a dictionary of its matrix
+    /// components. The matrix is stored as six little-endian doubles, or
+    /// floats on 32-bit systems.
+    ///
+    /// Returns `None` if the matrix is missing or malformed, so the caller can
+    /// fall back to decoding it as a regular class.
+    pub(crate) fn decode_affine_transform(&self, dict: &Dictionary) -> Option<Value> {
+        let bytes = dict
+            .get(TRANSFORM_STRUCT_KEY)
+            .and_then(|value| self.resolve_raw(value))
+            .and_then(|value| value.as_data())?;
+
+        let components: Vec<f64> = match bytes.len() {
+            48 => bytes
+                .chunks_exact(8)
+                .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+            24 => bytes
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()).into())
+                .collect(),
+            _ => return None,
+        };
+
+        let mut transform = Dictionary::new();
+        for (name, component) in TRANSFORM_FIELDS.iter().zip(components) {
+            transform.insert(name.to_string(), Value::Real(component));
+        }
+        Some(Value::Dictionary(transform))
+    }
+}
+
+/// Resolves a URL reference against a base URL as described in RFC 3986.
+fn join_url(base: &str, reference: &str) -> String {
+    // A reference with its own scheme is absolute already
+    if url_scheme(reference).is_some() {
+        return reference.to_string();
+    }
+    let Some(scheme) = url_scheme(base) else {
+        return reference.to_string();
+    };
+    if let Some(network_path) = reference.strip_prefix("//") {
+        return format!("{scheme}://{network_path}");
+    }
+
+    let after_scheme = &base[scheme.len() + 1..];
+    let (authority, base_path) = match after_scheme.strip_prefix("//") {
+        Some(rest) => {
+            let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+            (Some(&rest[..end]), &rest[end..])
+        }
+        None => (None, after_scheme),
+    };
+    let base_path = base_path.split(['?', '#']).next().unwrap_or_default();
+    let prefix = match authority {
+        Some(authority) => format!("{scheme}://{authority}"),
+        None => format!("{scheme}:"),
+    };
+
+    if reference.is_empty() || reference.starts_with('#') || reference.starts_with('?') {
+        let base = base.split('#').next().unwrap_or_default();
+        return match reference.starts_with('?') {
+            true => format!("{prefix}{base_path}{reference}"),
+            false => format!("{base}{reference}"),
+        };
+    }
+
+    let (path, suffix) = match reference.find(['?', '#']) {
+        Some(end) => reference.split_at(end),
+        None => (reference, ""),
+    };
+    let merged = if path.starts_with('/') {
+        path.to_string()
+    } else if authority.is_some() && base_path.is_empty() {
+        format!("/{path}")
+    } else {
+        let directory = base_path.rfind('/').map_or("", |end| &base_path[..=end]);
+        format!("{directory}{path}")
+    };
+    format!("{prefix}{}{suffix}", remove_dot_segments(&merged))
+}
+
+/// Returns the scheme of an absolute URL.
+fn url_scheme(url: &str) -> Option<&str> {
+    let scheme = &url[..url.find(':')?];
+    let mut chars = scheme.chars();
+    let is_valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    is_valid.then_some(scheme)
+}
+
+/// Resolves `.` and `..` segments of a URL path.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let parts: Vec<&str> = path.split('/').collect();
+    for (index, segment) in parts.iter().enumerate() {
+        let is_last = index + 1 == parts.len();
+        match *segment {
+            "." | ".." => {
+                if *segment == ".." && segments.len() > 1 {
+                    segments.pop();
+                }
+                // A trailing dot segment still denotes a directory
+                if is_last {
+                    segments.push("");
+                }
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+/// Formats `mantissa * 10^exponent` in decimal notation without trailing
+/// zeros after the decimal point. Returns `None` if the exponent is out of
+/// the range that `NSDecimal` stores, which is that of an `i8`.
+fn decimal_string(mantissa: u128, exponent: i64, is_negative: bool) -> Option<String> {
+    let exponent = i8::try_from(exponent).ok()?;
+    let digits = mantissa.to_string();
+    let mut s = match usize::from(exponent.unsigned_abs()) {
+        // A non-negative exponent only adds zeros
+        zeros if exponent >= 0 => format!("{digits}{}", "0".repeat(zeros)),
+        scale => {
+            let digits = format!("{digits:0>width$}", width = scale + 1);
+            let (whole, fraction) = digits.split_at(digits.len() - scale);
+            match fraction.trim_end_matches('0') {
+                "" => whole.to_string(),
+                fraction => format!("{whole}.{fraction}"),
+            }
+        }
+    };
+    if is_negative && mantissa != 0 {
+        s.insert(0, '-');
+    }
+    Some(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::*;
+    use crate::{ConverterError, ConverterOptions};
+    use plist::Value;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn date_objects(seconds: f64) -> Vec<Value> {
+        vec![
+            string("$null"),
+            object(2, vec![("NS.time", Value::Real(seconds))]),
+            class(&["NSDate", "NSObject"]),
+        ]
+    }
+
+    #[test]
+    fn dates_are_kept_as_raw_seconds_with_the_option() {
+        let converter = converter(date_objects(700_000_000.5));
+        let date = UNIX_EPOCH + Duration::from_secs_f64(978_307_200.0 + 700_000_000.5);
+        assert_eq!(decode_root(&converter), Value::Date(date.into()));
+
+        let options = ConverterOptions {
+            dates_as_raw_seconds: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(date_objects(700_000_000.5), options);
+        assert_eq!(decode_root(&converter), Value::Real(700_000_000.5));
+    }
+
+    #[test]
+    fn affine_transform_decodes_into_its_matrix() {
+        let matrix = [2.0, 0.5, -0.5, 2.0, 10.0, -20.25];
+        let bytes = matrix.iter().flat_map(|n: &f64| n.to_le_bytes()).collect();
+        let converter = converter(vec![
+            string("$null"),
+            object(2, vec![("NSTransformStruct", Value::Data(bytes))]),
+            class(&["NSAffineTransform", "NSObject"]),
+        ]);
+        let names = ["m11", "m12", "m21", "m22", "tX", "tY"];
+        assert_eq!(
+            decode_root(&converter),
+            dict(
+                names
+                    .into_iter()
+                    .zip(matrix)
+                    .map(|(name, n)| (name, Value::Real(n)))
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn dates_before_and_after_the_reference_date_decode() {
+        let cases = [
+            (0.0, "2001-01-01T00:00:00Z"),
+            (86_400.0 * 365.0, "2002-01-01T00:00:00Z"),
+            (-978_307_200.0 + 86_400.0, "1970-01-02T00:00:00Z"),
+            (-86_400.0, "2000-12-31T00:00:00Z"),
+        ];
+        for (seconds, expected) in cases {
+            let decoded = decode_root(&converter(date_objects(seconds)));
+            let Value::Date(date) = decoded else {
+                panic!("{seconds} isn't decoded into a date");
+            };
+            assert_eq!(date.to_xml_format(), expected);
+        }
+    }
+
+    #[test]
+    fn uuid_decodes_into_its_canonical_string() {
+        let uuid_objects = |bytes: Vec<u8>| {
+            vec![
+                string("$null"),
+                object(2, vec![("NS.uuidbytes", Value::Data(bytes))]),
+                class(&["NSUUID", "NSObject"]),
+            ]
+        };
+        let bytes = vec![
+            0x12, 0x3e, 0x45, 0x67, 0xe8, 0x9b, 0x12, 0xd3, 0xa4, 0x56, 0x42, 0x66, 0x14, 0x17,
+            0x40, 0x00,
+        ];
+        assert_eq!(
+            decode_root(&converter(uuid_objects(bytes))),
+            string("123e4567-e89b-12d3-a456-426614174000")
+        );
+
+        let converter = converter(uuid_objects(vec![0; 15]));
+        assert!(matches!(
+            converter.decode(),
+            Err(ConverterError::InvalidObjectEncoding { object_ref: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn urls_decode_into_strings() {
+        let url_class = || class(&["NSURL", "NSObject"]);
+        let plain = converter(vec![
+            string("$null"),
+            object(2, vec![("NS.base", uid(0)), ("NS.relative", uid(3))]),
+            url_class(),
+            string("file:///Users/alice/Documents/notes.txt"),
+        ]);
+        assert_eq!(
+            decode_root(&plain),
+            string("file:///Users/alice/Documents/notes.txt")
+        );
+
+        let relative = converter(vec![
+            string("$null"),
+            object(2, vec![("NS.base", uid(3)), ("NS.relative", uid(4))]),
+            url_class(),
+            object(2, vec![("NS.base", uid(0)), ("NS.relative", uid(5))]),
+            string("../images/logo.png?size=2"),
+            string("https://example.com/docs/guide/index.html"),
+        ]);
+        assert_eq!(
+            decode_root(&relative),
+            string("https://example.com/docs/images/logo.png?size=2")
+        );
+    }
+
+    #[test]
+    fn iso_date_strings_are_written_to_xml() {
+        let options = ConverterOptions {
+            dates_as_iso_strings: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(date_objects(86_400.0), options);
+        let decoded = converter.decode().unwrap();
+        let mut xml = Vec::new();
+        decoded.to_writer_xml(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(
+            xml.contains("<string>2001-01-02T00:00:00Z</string>"),
+            "{xml}"
+        );
+        assert!(!xml.contains("<date>"));
+    }
+
+    #[test]
+    fn attributed_string_decodes_into_text_and_attributes() {
+        let converter = converter(vec![
+            string("$null"),
+            object(2, vec![("NSString", uid(3)), ("NSAttributes", uid(4))]),
+            class(&["NSAttributedString", "NSObject"]),
+            string("Hello"),
+            dictionary(5, &[6], &[7]),
+            class(&["NSDictionary", "NSObject"]),
+            string("NSColor"),
+            string("red"),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            dict(vec![
+                ("string", string("Hello")),
+                (
+                    "attributes",
+                    Value::Array(vec![dict(vec![
+                        ("key", string("NSColor")),
+                        ("value", string("red")),
+                    ])])
+                ),
+            ])
+        );
+    }
+
+    fn decimal(mantissa: u16, exponent: i64, is_negative: bool) -> Value {
+        decode_root(&converter(vec![
+            string("$null"),
+            object(
+                2,
+                vec![
+                    ("NS.mantissa", Value::Data(mantissa.to_le_bytes().to_vec())),
+                    ("NS.mantissa.bo", int(1)),
+                    ("NS.exponent", int(exponent)),
+                    ("NS.length", int(1)),
+                    ("NS.negative", Value::Boolean(is_negative)),
+                ],
+            ),
+            class(&["NSDecimalNumber", "NSNumber", "NSObject"]),
+        ]))
+    }
+
+    #[test]
+    fn whole_decimal_number_keeps_its_zeros() {
+        assert_eq!(decimal(12345, 0, false), string("12345"));
+        assert_eq!(decimal(12345, 2, false), string("1234500"));
+        assert_eq!(decimal(12000, -3, false), string("12"));
+    }
+
+    #[test]
+    fn fractional_decimal_number_is_exact() {
+        assert_eq!(decimal(12345, -2, false), string("123.45"));
+        assert_eq!(decimal(5, -3, false), string("0.005"));
+    }
+
+    #[test]
+    fn negative_decimal_number_has_a_sign() {
+        assert_eq!(decimal(12345, -2, true), string("-123.45"));
+        assert_eq!(decimal(0, 0, true), string("0"));
+    }
+
+    #[test]
+    fn decimal_number_with_an_exponent_out_of_range_is_a_class() {
+        for exponent in [i64::MAX, i64::MIN, 128, -129] {
+            let decoded = decimal(1, exponent, false).into_dictionary().unwrap();
+            assert_eq!(decoded["NS.exponent"], int(exponent));
+            assert!(decoded.contains_key("$classes"));
+        }
+    }
+}
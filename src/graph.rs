@@ -0,0 +1,215 @@
+//! Helpers that work on the raw object graph of an archive.
+
+use crate::{
+    with_stack_guard, Converter, ConverterError, ARCHIVER, ARCHIVER_KEY_NAME, ARCHIVER_VERSION,
+    NULL_OBJECT_REFERENCE_NAME, OBJECTS_KEY_NAME, TOP_KEY_NAME, VERSION_KEY_NAME,
+};
+use plist::{Dictionary, Uid, Value};
+use std::collections::{HashMap, VecDeque};
+
+/// A `$top` key of an extracted subarchive.
+const SUBARCHIVE_ROOT_KEY: &str = "root";
+
+impl Converter {
+    /// Returns all references between entries of `$objects` as
+    /// `(from_index, to_index)` pairs, ordered by the referencing object.
+    ///
+    /// This includes `$class` references, references to `$null` and
+    /// references nested inside arrays.
+    pub fn object_ref_edges(&self) -> Vec<(u64, u64)> {
+        self.objects
+            .iter()
+            .enumerate()
+            .flat_map(|(from, object)| {
+                object_references(object)
+                    .into_iter()
+                    .map(move |to| (from as u64, to))
+            })
+            .collect()
+    }
+
+    /// Returns the number of references to an entry of `$objects` from other
+    /// objects and from `$top`, including `$class` references.
+    pub fn reference_count(&self, index: u64) -> usize {
+        self.reference_counts
+            .get(&index)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the references that point outside of `$objects` as
+    /// `(from_index, to_index)` pairs, without decoding anything.
+    pub fn dangling_references(&self) -> Vec<(u64, u64)> {
+        let len = self.objects.len() as u64;
+        self.object_ref_edges()
+            .into_iter()
+            .filter(|&(_, to)| to >= len)
+            .collect()
+    }
+
+    /// Extracts an object and all objects reachable from it into a standalone
+    /// NSKeyedArchiver plist.
+    ///
+    /// Objects are re-indexed in the order they're reached and references are
+    /// rebased accordingly. The extracted object is stored under the `root`
+    /// key of `$top`.
+    pub fn extract_subarchive(&self, root_index: u64) -> Result<Value, ConverterError> {
+        if root_index == 0 || self.get_object(root_index).is_none() {
+            return Err(ConverterError::InvalidObjectReference(root_index));
+        }
+
+        // Index 0 is reserved for `$null` in every archive
+        let mut new_indices = HashMap::from([(0, 0)]);
+        let mut order = Vec::new();
+        let mut queue = VecDeque::from([root_index]);
+        while let Some(index) = queue.pop_front() {
+            if new_indices.contains_key(&index) {
+                continue;
+            }
+            let Some(object) = self.get_object(index) else {
+                return Err(ConverterError::InvalidObjectReference(index));
+            };
+            new_indices.insert(index, order.len() as u64 + 1);
+            order.push(object);
+            queue.extend(object_references(object));
+        }
+
+        let mut objects = Vec::with_capacity(order.len() + 1);
+        objects.push(Value::String(NULL_OBJECT_REFERENCE_NAME.to_string()));
+        for object in order {
+            objects.push(rebase_references(object, &new_indices));
+        }
+
+        let mut top = Dictionary::new();
+        top.insert(
+            SUBARCHIVE_ROOT_KEY.to_string(),
+            Value::Uid(Uid::new(new_indices[&root_index])),
+        );
+
+        let mut archive = Dictionary::new();
+        archive.insert(
+            VERSION_KEY_NAME.to_string(),
+            Value::Integer(ARCHIVER_VERSION.into()),
+        );
+        archive.insert(
+            ARCHIVER_KEY_NAME.to_string(),
+            Value::String(ARCHIVER.to_string()),
+        );
+        archive.insert(TOP_KEY_NAME.to_string(), Value::Dictionary(top));
+        archive.insert(OBJECTS_KEY_NAME.to_string(), Value::Array(objects));
+        Ok(Value::Dictionary(archive))
+    }
+}
+
+/// Collects all references held by a raw object, including its `$class`.
+pub(crate) fn object_references(value: &Value) -> Vec<u64> {
+    let mut references = Vec::new();
+    collect_references(value, &mut references);
+    references
+}
+
+fn collect_references(value: &Value, references: &mut Vec<u64>) {
+    with_stack_guard(|| collect_references_unguarded(value, references))
+}
+
+fn collect_references_unguarded(value: &Value, references: &mut Vec<u64>) {
+    match value {
+        Value::Uid(uid) => references.push(uid.get()),
+        Value::Array(array) => {
+            for element in array {
+                collect_references(element, references);
+            }
+        }
+        Value::Dictionary(dict) => {
+            for element in dict.values() {
+                collect_references(element, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Clones a raw object replacing its references with new indices.
+fn rebase_references(value: &Value, new_indices: &HashMap<u64, u64>) -> Value {
+    with_stack_guard(|| rebase_references_unguarded(value, new_indices))
+}
+
+fn rebase_references_unguarded(value: &Value, new_indices: &HashMap<u64, u64>) -> Value {
+    match value {
+        Value::Uid(uid) => Value::Uid(Uid::new(new_indices[&uid.get()])),
+        Value::Array(array) => Value::Array(
+            array
+                .iter()
+                .map(|element| rebase_references(element, new_indices))
+                .collect(),
+        ),
+        Value::Dictionary(dict) => {
+            let mut rebased = Dictionary::new();
+            for (key, element) in dict {
+                rebased.insert(key.clone(), rebase_references(element, new_indices));
+            }
+            Value::Dictionary(rebased)
+        }
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::*;
+    use crate::Converter;
+    use plist::Value;
+
+    fn people() -> Converter {
+        converter(vec![
+            string("$null"),
+            array(3, &[2, 5]),
+            object(4, vec![("name", uid(6))]),
+            class(&["NSArray", "NSObject"]),
+            class(&["Person", "NSObject"]),
+            string("Bob"),
+            string("Alice"),
+        ])
+    }
+
+    #[test]
+    fn extracted_subarchive_decodes_independently() {
+        let subarchive = people().extract_subarchive(2).unwrap();
+        let objects = subarchive.as_dictionary().unwrap()["$objects"]
+            .as_array()
+            .unwrap();
+        assert_eq!(objects.len(), 4);
+
+        let converter = Converter::new(subarchive).unwrap();
+        assert_eq!(
+            decode_root(&converter),
+            dict(vec![
+                (
+                    "$classes",
+                    Value::Array(vec![string("Person"), string("NSObject")])
+                ),
+                ("name", string("Alice")),
+            ])
+        );
+    }
+
+    #[test]
+    fn object_ref_edges_include_class_references() {
+        assert_eq!(
+            people().object_ref_edges(),
+            [(1, 3), (1, 2), (1, 5), (2, 4), (2, 6)]
+        );
+    }
+
+    #[test]
+    fn dangling_references_are_reported() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 9]),
+            class(&["NSArray", "NSObject"]),
+            object(2, vec![("owner", uid(4))]),
+        ]);
+        assert_eq!(converter.dangling_references(), [(1, 9), (3, 4)]);
+        assert!(people().dangling_references().is_empty());
+    }
+}
@@ -0,0 +1,406 @@
+//! Conversion of decoded values to JSON.
+
+use crate::{
+    drop_iteratively, stringify_scalars, with_stack_guard, Converter, ConverterError, DecodeState,
+    DictStyle,
+};
+use base64::Engine;
+use plist::Value;
+use serde_json::{Map, Number, Value as JsonValue};
+use std::io::Write;
+
+/// The largest integer that JavaScript numbers represent exactly, `2^53 - 1`.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// Converts a [plist::Value] to a [serde_json::Value].
+///
+/// Types that JSON lacks are mapped as follows:
+/// - data becomes a base64 encoded string;
+/// - a date becomes an ISO 8601 string, e.g. `2001-01-01T00:00:00Z`;
+/// - a uid becomes a `{"CF$UID": <uid>}` object;
+/// - a non-finite real number becomes `null`.
+pub fn to_json_value(value: &Value) -> JsonValue {
+    with_stack_guard(|| to_json_value_unguarded(value))
+}
+
+/// Converts a [plist::Value] to a JSON string, mapping types as
+/// [to_json_value] does.
+pub fn to_json_string(value: &Value) -> Result<String, ConverterError> {
+    let json = to_json_value(value);
+    Ok(serde_json::to_string(&json)?)
+}
+
+/// Converts a [plist::Value] to an indented JSON string, mapping types as
+/// [to_json_value] does.
+pub fn to_json_string_pretty(value: &Value) -> Result<String, ConverterError> {
+    let json = to_json_value(value);
+    Ok(serde_json::to_string_pretty(&json)?)
+}
+
+fn to_json_value_unguarded(value: &Value) -> JsonValue {
+    match value {
+        Value::Array(array) => JsonValue::Array(array.iter().map(to_json_value).collect()),
+        Value::Dictionary(dict) => JsonValue::Object(
+            dict.iter()
+                .map(|(key, value)| (key.clone(), to_json_value(value)))
+                .collect(),
+        ),
+        Value::Boolean(b) => JsonValue::Bool(*b),
+        Value::Data(data) => {
+            JsonValue::String(base64::engine::general_purpose::STANDARD.encode(data))
+        }
+        Value::Date(date) => JsonValue::String(date.to_xml_format()),
+        Value::Real(real) => Number::from_f64(*real).map_or(JsonValue::Null, JsonValue::Number),
+        Value::Integer(integer) => match integer.as_signed() {
+            Some(signed) => JsonValue::Number(signed.into()),
+            None => JsonValue::Number(integer.as_unsigned().unwrap_or_default().into()),
+        },
+        Value::String(s) => JsonValue::String(s.clone()),
+        Value::Uid(uid) => {
+            let mut object = Map::new();
+            object.insert("CF$UID".to_string(), JsonValue::Number(uid.get().into()));
+            JsonValue::Object(object)
+        }
+        _ => JsonValue::Null,
+    }
+}
+
+impl Converter {
+    /// Decodes a NSKeyedArchiver encoded plist into a [serde_json::Value].
+    ///
+    /// Values are converted with [to_json_value]. Unless another
+    /// [crate::DictStyle] is set, dictionaries with unique string keys become
+    /// JSON objects and the rest become arrays of `key`/`value` pairs.
+    /// See also [crate::ConverterOptions::large_ints_as_strings].
+    pub fn decode_to_json(&self) -> Result<JsonValue, ConverterError> {
+        self.start_decoding();
+        let mut state = DecodeState::default();
+        if self.dict_style == DictStyle::KeyValuePairs {
+            state.dict_style = Some(DictStyle::Auto);
+        }
+        let decoded = self.decode_top(&mut state)?;
+        let mut json = to_json_value(&decoded);
+        drop_iteratively(decoded);
+        if self.options.large_ints_as_strings {
+            stringify_large_ints(&mut json);
+        }
+        Ok(json)
+    }
+
+    /// Decodes an array object and writes it to `writer` as a JSON array.
+    ///
+    /// Elements are decoded and written one at a time, so only a single
+    /// decoded element is kept in memory. `root_index` is the index of a
+    /// `NSArray` in `$objects`.
+    ///
+    /// Elements come out the same as the elements of the array in
+    /// [Converter::decode_to_json]: `$null` elements are skipped, or written
+    /// as `"$null"` with [crate::ConverterOptions::leave_null_values]. With
+    /// [crate::ConverterOptions::leave_uid_references] every element is
+    /// written as a `{"CF$UID": <uid>}` reference, and a reference back to
+    /// the array itself is a circular reference otherwise.
+    pub fn decode_array_streaming_to_writer<W: Write>(
+        &self,
+        root_index: u64,
+        mut writer: W,
+    ) -> Result<(), ConverterError> {
+        let Some(object) = self.get_object(root_index) else {
+            return Err(ConverterError::InvalidObjectReference(root_index));
+        };
+        let Some(dict) = object.as_dictionary() else {
+            return Err(ConverterError::invalid_encoding(root_index));
+        };
+        let Some(class_reference) = dict
+            .get(&self.reserved_keys.class)
+            .and_then(|class| self.as_reference(class))
+        else {
+            return Err(ConverterError::invalid_encoding(root_index));
+        };
+        let class_names = self.get_class_names(&class_reference)?;
+        self.check_class_allowed(&class_names)?;
+        if !matches!(
+            class_names.first(),
+            Some(&("NSArray" | "NSMutableArray" | "__NSCFArray"))
+        ) {
+            return Err(ConverterError::invalid_encoding(root_index));
+        }
+        let Some(elements) = dict
+            .get(&self.reserved_keys.objects)
+            .and_then(|objs| objs.as_array())
+        else {
+            return Err(ConverterError::invalid_encoding(root_index));
+        };
+
+        self.check_collection_len(root_index, elements.len())?;
+
+        self.start_decoding();
+        let mut state = DecodeState::default();
+        state.parents.insert(root_index);
+
+        writer.write_all(b"[")?;
+        let mut is_first = true;
+        for (index, element) in elements.iter().enumerate() {
+            let uid = self.expect_uid(element, &self.reserved_keys.objects)?;
+            let Some(mut value) = self
+                .decode_object(&uid, &mut state)
+                .map_err(|e| e.at(&format!("{}[{index}]", self.reserved_keys.objects)))?
+            else {
+                continue;
+            };
+            if self.options.stringify_all_scalars {
+                stringify_scalars(&mut value);
+            }
+            if !is_first {
+                writer.write_all(b",")?;
+            }
+            is_first = false;
+            let json = to_json_value(&value);
+            drop_iteratively(value);
+            serde_json::to_writer(&mut writer, &json)?;
+        }
+        writer.write_all(b"]")?;
+        Ok(())
+    }
+}
+
+/// Converts integers that JavaScript can't represent exactly into strings.
+fn stringify_large_ints(json: &mut JsonValue) {
+    with_stack_guard(|| stringify_large_ints_unguarded(json))
+}
+
+fn stringify_large_ints_unguarded(json: &mut JsonValue) {
+    match json {
+        JsonValue::Number(number) => {
+            let is_safe = match number.as_i64() {
+                Some(integer) => integer.unsigned_abs() <= MAX_SAFE_INTEGER as u64,
+                // Reals and integers above `i64::MAX`
+                None => number.is_f64(),
+            };
+            if !is_safe {
+                *json = JsonValue::String(number.to_string());
+            }
+        }
+        JsonValue::Array(array) => array.iter_mut().for_each(stringify_large_ints),
+        JsonValue::Object(object) => object.values_mut().for_each(stringify_large_ints),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use crate::ConverterOptions;
+    use serde_json::json;
+
+    #[test]
+    fn integer_keyed_dictionary_becomes_a_json_object() {
+        let options = ConverterOptions {
+            stringify_integer_keys: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                dictionary(2, &[3, 4], &[5, 6]),
+                class(&["NSDictionary", "NSObject"]),
+                int(10),
+                int(-2),
+                string("ten"),
+                string("minus two"),
+            ],
+            options,
+        );
+        assert_eq!(
+            to_json_value(&converter.decode().unwrap()),
+            json!({"root": {"10": "ten", "-2": "minus two"}})
+        );
+    }
+
+    #[test]
+    fn streamed_array_parses_back_equal() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 0, 4, 5]),
+            class(&["NSArray", "NSObject"]),
+            string("Alice"),
+            int(42),
+            array(2, &[3]),
+        ]);
+        let mut streamed = Vec::new();
+        converter
+            .decode_array_streaming_to_writer(1, &mut streamed)
+            .unwrap();
+        let streamed: JsonValue = serde_json::from_slice(&streamed).unwrap();
+        assert_eq!(streamed, to_json_value(&decode_root(&converter)));
+        assert_eq!(streamed, json!(["Alice", 42, ["Alice"]]));
+
+        assert!(converter
+            .decode_array_streaming_to_writer(3, &mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn streamed_array_keeps_null_elements_with_the_option() {
+        let objects = vec![
+            string("$null"),
+            array(2, &[3, 0, 1]),
+            class(&["NSArray", "NSObject"]),
+            string("Alice"),
+        ];
+        let options = ConverterOptions {
+            leave_null_values: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(objects.clone(), options);
+        let mut streamed = Vec::new();
+        converter
+            .decode_array_streaming_to_writer(1, &mut streamed)
+            .unwrap();
+        let streamed: JsonValue = serde_json::from_slice(&streamed).unwrap();
+        assert_eq!(streamed, to_json_value(&decode_root(&converter)));
+        assert_eq!(
+            streamed,
+            json!(["Alice", "$null", "circular reference for uid #1"])
+        );
+
+        let options = ConverterOptions {
+            leave_uid_references: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(objects, options);
+        let mut streamed = Vec::new();
+        converter
+            .decode_array_streaming_to_writer(1, &mut streamed)
+            .unwrap();
+        let streamed: JsonValue = serde_json::from_slice(&streamed).unwrap();
+        assert_eq!(streamed, to_json_value(&decode_root(&converter)));
+        assert_eq!(
+            streamed,
+            json!([{"CF$UID": 3}, {"CF$UID": 0}, {"CF$UID": 1}])
+        );
+    }
+
+    #[test]
+    fn string_keyed_dictionary_becomes_a_json_object() {
+        let converter = converter(vec![
+            string("$null"),
+            dictionary(2, &[3, 4], &[5, 6]),
+            class(&["NSDictionary", "NSObject"]),
+            string("name"),
+            string("age"),
+            string("Alice"),
+            int(42),
+        ]);
+        assert_eq!(
+            converter.decode_to_json().unwrap(),
+            json!({"root": {"name": "Alice", "age": 42}})
+        );
+    }
+
+    #[test]
+    fn dictionary_with_duplicate_keys_stays_pairs_in_json() {
+        let converter = converter(vec![
+            string("$null"),
+            dictionary(2, &[3, 3], &[4, 5]),
+            class(&["NSDictionary", "NSObject"]),
+            string("name"),
+            string("Alice"),
+            string("Bob"),
+        ]);
+        assert_eq!(
+            converter.decode_to_json().unwrap(),
+            json!({"root": [
+                {"key": "name", "value": "Alice"},
+                {"key": "name", "value": "Bob"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn array_of_key_value_dictionaries_is_not_folded() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3]),
+            class(&["NSArray", "NSObject"]),
+            dictionary(4, &[5, 6], &[7, 8]),
+            class(&["NSDictionary", "NSObject"]),
+            string("key"),
+            string("value"),
+            string("color"),
+            string("red"),
+        ]);
+        assert_eq!(
+            converter.decode_to_json().unwrap(),
+            json!({"root": [{"key": "color", "value": "red"}]})
+        );
+    }
+
+    #[test]
+    fn integers_beyond_javascript_precision_become_strings() {
+        let objects = vec![
+            string("$null"),
+            array(2, &[3, 4, 5, 6]),
+            class(&["NSArray", "NSObject"]),
+            int(9007199254740993),
+            int(-9007199254740993),
+            int(9007199254740991),
+            int(i64::MIN),
+        ];
+        let converter = converter(objects.clone());
+        assert_eq!(
+            converter.decode_to_json().unwrap(),
+            json!({"root": [
+                9007199254740993i64,
+                -9007199254740993i64,
+                9007199254740991i64,
+                i64::MIN,
+            ]})
+        );
+
+        let options = ConverterOptions {
+            large_ints_as_strings: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(objects, options);
+        assert_eq!(
+            converter.decode_to_json().unwrap(),
+            json!({"root": [
+                "9007199254740993",
+                "-9007199254740993",
+                9007199254740991i64,
+                i64::MIN.to_string(),
+            ]})
+        );
+    }
+
+    #[test]
+    fn very_deep_archive_fails_cleanly_as_json() {
+        let mut converter = converter(nested_dictionaries(50_000));
+        converter.set_max_depth(Some(crate::RECOMMENDED_MAX_DEPTH));
+        assert!(matches!(
+            converter.decode_to_json(),
+            Err(ConverterError::MaxDepthExceeded(
+                crate::RECOMMENDED_MAX_DEPTH
+            ))
+        ));
+    }
+
+    #[test]
+    fn data_and_dates_become_base64_and_iso_strings() {
+        let date = std::time::UNIX_EPOCH + std::time::Duration::from_secs(978_307_200 + 60);
+        let value = dict(vec![
+            ("data", Value::Data(vec![0, 1, 2, 255])),
+            ("date", Value::Date(date.into())),
+        ]);
+        let expected = json!({"data": "AAEC/w==", "date": "2001-01-01T00:01:00Z"});
+
+        let compact = to_json_string(&value).unwrap();
+        assert_eq!(compact, expected.to_string());
+        let pretty = to_json_string_pretty(&value).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&pretty).unwrap(),
+            expected
+        );
+    }
+}
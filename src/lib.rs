@@ -1,7 +1,45 @@
+use base64::Engine;
+#[cfg(feature = "jsonschema")]
+pub use jsonschema;
 pub use plist;
 use plist::{Dictionary, Uid, Value};
+#[cfg(feature = "json")]
+pub use serde_json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use thiserror::Error;
 
+mod builder;
+mod diff;
+mod encoder;
+#[cfg(test)]
+mod fixtures;
+mod foundation;
+mod graph;
+#[cfg(feature = "json")]
+mod json;
+mod nsvalue;
+mod registry;
+mod report;
+#[cfg(feature = "jsonschema")]
+mod schema;
+#[cfg(feature = "serde")]
+mod serialize;
+mod swift;
+mod tree;
+
+pub use builder::ConverterBuilder;
+pub use diff::{diff, Difference};
+pub use encoder::Encoder;
+#[cfg(feature = "json")]
+pub use json::{to_json_string, to_json_string_pretty, to_json_value};
+pub use report::{MemoryReport, TypeStats};
+#[cfg(feature = "jsonschema")]
+pub use schema::{validate_output, Schema};
+#[cfg(feature = "serde")]
+pub use serialize::SerializableValue;
+pub use tree::Node;
+
 const ARCHIVER: &str = "NSKeyedArchiver";
 const ARCHIVER_VERSION: u64 = 100000;
 
@@ -10,8 +48,36 @@ const TOP_KEY_NAME: &str = "$top";
 const OBJECTS_KEY_NAME: &str = "$objects";
 const VERSION_KEY_NAME: &str = "$version";
 const NULL_OBJECT_REFERENCE_NAME: &str = "$null";
+const REFERENCE_KEY_NAME: &str = "$ref";
+const SOURCE_UID_KEY_NAME: &str = "$uid";
+const REFERENCE_COUNT_KEY_NAME: &str = "$refcount";
+const MUTABLE_KEY_NAME: &str = "$mutable";
+const CLASS_NAME_KEY_NAME: &str = "$classname";
+const NS_STRING_KEY_NAME: &str = "NS.string";
+/// Keys that hold the fields encoded by a superclass in a nested object.
+const SUPER_KEY_NAMES: [&str; 2] = ["$super", "NS.super"];
+const SUPER_OUTPUT_KEY_NAME: &str = "super";
+/// A value that replaces an object that failed to decode, see
+/// [Converter::decode_lossy].
+const DECODE_ERROR_PLACEHOLDER: &str = "<decode error>";
+const DICT_MAP_KEY_NAME: &str = "$map";
+const DICT_PAIRS_KEY_NAME: &str = "$pairs";
+
+/// Leading bytes of a binary plist.
+const BINARY_PLIST_MAGIC: &[u8] = b"bplist00";
+/// Leading bytes of a XML plist.
+const XML_PLIST_PROLOG: &[u8] = b"<?xml";
+
+const PREFIX_TABLE_KEY_NAME: &str = "NS.prefixtable";
+const PREFIX_INDEX_KEY_NAME: &str = "NS.prefixindex";
+const PREFIX_SUFFIX_KEY_NAME: &str = "NS.suffix";
 
+/// An error that occurs while reading or decoding an archive.
+///
+/// Some variants only exist with optional features enabled, so the enum is
+/// non-exhaustive to keep the features additive.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum ConverterError {
     #[error("Plist error: {0}")]
     PlistError(String),
@@ -25,12 +91,78 @@ pub enum ConverterError {
     UnsupportedArchiverVersion,
     #[error("Invalid object reference ({0}). The data may be corrupt.")]
     InvalidObjectReference(u64),
-    #[error("Invalid object encoding ({0}). The data may be corrupt.")]
-    InvalidObjectEncoding(u64),
+    /// `path` leads to the object from a top level key through the raw keys
+    /// of the archive, e.g. `root.NS.objects[3].$class`.
+    #[error("Invalid object encoding ({object_ref}) at '{path}'. The data may be corrupt.")]
+    InvalidObjectEncoding { object_ref: u64, path: String },
     #[error("Invalid class reference ({0}). The data may be corrupt.")]
     InvalidClassReference(String),
+    #[error("Invalid class object ({0}). Expected a dictionary with a '$classes' key.")]
+    InvalidClassObject(u64),
     #[error("Expected uid value for key {0}")]
     ExpectedUIDValue(String),
+    #[error("Class '{0}' isn't in the allowlist")]
+    DisallowedClass(String),
+    #[error("IO error: {0}")]
+    IoError(String),
+    #[error("Object ({0}) references itself through its own members")]
+    CyclicReference(u64),
+    #[error("Objects are nested deeper than the limit of {0}")]
+    MaxDepthExceeded(usize),
+    #[error("Collection ({index}) has {len} elements, more than the limit allows")]
+    CollectionTooLarge { index: u64, len: usize },
+    #[error("Expected a 4-byte length prefix")]
+    MissingLengthPrefix,
+    #[error("Length prefix ({0}) doesn't match the payload length ({1})")]
+    LengthPrefixMismatch(u32, usize),
+    #[cfg(feature = "json")]
+    #[error("JSON error: {0}")]
+    JsonError(String),
+    #[cfg(feature = "zip")]
+    #[error("Zip error: {0}")]
+    ZipError(String),
+    #[cfg(feature = "jsonschema")]
+    #[error("Output doesn't match the schema: {0}")]
+    SchemaValidation(String),
+}
+
+impl ConverterError {
+    /// Creates a [ConverterError::InvalidObjectEncoding] error. Its path is
+    /// filled in by [ConverterError::at] as the error is passed up.
+    pub(crate) fn invalid_encoding(object_ref: u64) -> Self {
+        Self::InvalidObjectEncoding {
+            object_ref,
+            path: String::new(),
+        }
+    }
+
+    /// Prepends a key, or an index like `[3]`, to the path of an error that
+    /// was returned by a nested object.
+    pub(crate) fn at(mut self, segment: &str) -> Self {
+        self.prepend_path(segment);
+        self
+    }
+
+    /// Checks if an error is caused by a limit or a policy of the caller
+    /// rather than by a malformed archive. Those are never recovered from.
+    pub(crate) fn is_policy_violation(&self) -> bool {
+        matches!(
+            self,
+            Self::DisallowedClass(_)
+                | Self::CyclicReference(_)
+                | Self::CollectionTooLarge { .. }
+                | Self::MaxDepthExceeded(_)
+        )
+    }
+
+    fn prepend_path(&mut self, segment: &str) {
+        if let Self::InvalidObjectEncoding { path, .. } = self {
+            if !path.is_empty() && !path.starts_with('[') {
+                path.insert(0, '.');
+            }
+            path.insert_str(0, segment);
+        }
+    }
 }
 
 impl From<plist::Error> for ConverterError {
@@ -42,36 +174,352 @@ impl From<plist::Error> for ConverterError {
     }
 }
 
-macro_rules! uid {
-    ($name:ident, $key:expr) => {
-        match ($name.as_uid()) {
-            Some(u) => u,
-            None => return Err(ConverterError::ExpectedUIDValue($key)),
-        }
-    };
+impl From<std::io::Error> for ConverterError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IoError(value.to_string())
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for ConverterError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::JsonError(value.to_string())
+    }
+}
+
+#[cfg(feature = "zip")]
+impl From<zip::result::ZipError> for ConverterError {
+    fn from(value: zip::result::ZipError) -> Self {
+        Self::ZipError(value.to_string())
+    }
 }
 
 /// Converts NSKeyedArchiver encoded plists to a human readable [plist::Value]
 /// structure.
 ///
-/// ```rust
+/// ```rust,no_run
 /// use nskeyedarchiver_converter::Converter;
 ///
 /// let decoded_file = Converter::from_file("foo.bin")?.decode()?;
 /// /// Now you can export it using plist::Value methods
 /// decoded_file.to_file_xml("foo.plist")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
 /// ```
 pub struct Converter {
     objects: Vec<Value>,
     top: Dictionary,
-    treat_all_as_classes: bool,
-    leave_null_values: bool,
+    options: ConverterOptions,
+    /// An input that isn't a NSKeyedArchiver plist, see
+    /// [ConverterOptions::try_passthrough].
+    passthrough: Option<Value>,
+    string_transform: Option<StringTransform>,
+    object_transform: Option<ObjectTransform>,
+    class_allowlist: Option<Vec<String>>,
+    reserved_keys: ReservedKeys,
+    dict_style: DictStyle,
+    /// Whether `$top` was an array, see [ConverterOptions::lenient_top].
+    top_is_array: bool,
+    /// Numbers of references to each object, see
+    /// [Converter::reference_count].
+    reference_counts: HashMap<u64, usize>,
+    /// Decoded containers keyed by their object reference, along with the
+    /// number of nested container levels they span.
+    cache: Mutex<DecodeCache>,
+    warning_sink: Option<Mutex<WarningSink>>,
+}
+
+type StringTransform = Box<dyn Fn(&str) -> String + Send + Sync>;
+type ObjectTransform = Box<dyn Fn(&str, Value) -> Value + Send + Sync>;
+type WarningSink = Box<dyn FnMut(&str) + Send>;
+type DecodeCache = HashMap<u64, (Option<Value>, usize)>;
+
+/// Names of the keys that objects of an archive are built with. The defaults
+/// match the ones used by `NSKeyedArchiver`, see
+/// [Converter::set_reserved_keys].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReservedKeys {
+    /// A key of an object that references its class object.
+    pub class: String,
+    /// A key of a class object that holds its class chain.
+    pub classes: String,
+    /// A key that holds the elements of an array or the values of a
+    /// dictionary.
+    pub objects: String,
+    /// A key that holds the keys of a dictionary.
+    pub keys: String,
+}
+
+impl Default for ReservedKeys {
+    fn default() -> Self {
+        Self {
+            class: "$class".to_string(),
+            classes: "$classes".to_string(),
+            objects: "NS.objects".to_string(),
+            keys: "NS.keys".to_string(),
+        }
+    }
+}
+
+/// A form that dictionaries are decoded into, see [Converter::set_dict_style].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DictStyle {
+    /// A dictionary if all keys are unique strings, an array of `key`/`value`
+    /// pairs otherwise.
+    Auto,
+    /// Always a dictionary. Numbers and booleans used as keys are converted
+    /// into strings, pairs with other keys are dropped. A duplicate key
+    /// replaces the previous pair.
+    Map,
+    /// Always an array of `key`/`value` pairs, since a key can be of any type.
+    #[default]
+    KeyValuePairs,
+}
+
+/// The header of an archive, see [Converter::metadata].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveMetadata {
+    pub archiver: String,
+    pub version: u64,
+    /// Keys of `$top` in their original order. If `$top` was an array, these
+    /// are the indices of its elements.
+    pub top_keys: Vec<String>,
+    /// The number of entries in `$objects`, including `$null`.
+    pub object_count: usize,
+}
+
+/// Limits on the size of decoded values, see [ConverterOptions::limits].
+/// Nothing is limited by default.
+#[derive(Clone, Debug, Default)]
+pub struct DecodeLimits {
+    /// Fail with [ConverterError::CollectionTooLarge] if an array, a set or
+    /// a dictionary has more elements than this.
+    pub max_collection_len: Option<usize>,
+}
+
+/// A reasonable value of [ConverterOptions::max_depth] for archives from
+/// untrusted sources. Real archives are rarely nested this deep.
+pub const RECOMMENDED_MAX_DEPTH: usize = 512;
+
+/// Options that control how a [Converter] reads and decodes an archive.
+#[derive(Clone, Debug, Default)]
+pub struct ConverterOptions {
+    /// Treat dictionaries and arrays as regular classes. A `$classes` key gets
+    /// retained. By default those are transformed into native plist structures.
+    pub treat_all_as_classes: bool,
+    /// Leave `$null` values. By default they're omitted.
+    pub leave_null_values: bool,
+    /// Leave references inside of objects as [plist::Value::Uid]s instead of
+    /// following them, including references to `$null`. Only the objects of
+    /// the top level keys are decoded. Useful to inspect the raw structure of
+    /// a corrupt archive.
+    pub leave_uid_references: bool,
+    /// If the input isn't a NSKeyedArchiver plist, create a converter anyway
+    /// and return the input unchanged when decoding. By default such inputs
+    /// are rejected with an error.
+    pub try_passthrough: bool,
+    /// Reconstruct strings that are compressed with a shared prefix table.
+    /// Such strings are objects with a `NS.prefixtable` key referencing an
+    /// array of prefixes, a `NS.prefixindex` key and a `NS.suffix` key. By
+    /// default those are decoded as regular classes.
+    pub expand_prefix_strings: bool,
+    /// Decode dictionaries whose keys are all integers into a dictionary with
+    /// stringified keys, e.g. for a JSON export. By default every dictionary
+    /// is decoded into an array of `key`/`value` pairs.
+    pub stringify_integer_keys: bool,
+    /// Accept an archive with a `$top` array of references instead of a
+    /// dictionary. Its elements are decoded into an array. By default such
+    /// archives are rejected with an error.
+    pub lenient_top: bool,
+    /// Keep dates as seconds since the Cocoa reference date (2001-01-01) in a
+    /// [plist::Value::Real]. By default dates are decoded into a
+    /// [plist::Value::Date].
+    pub dates_as_raw_seconds: bool,
+    /// Decode dates into ISO 8601 strings in UTC, e.g. `2001-01-01T00:00:00Z`,
+    /// the same format that `Converter::decode_to_json` uses. Useful to get
+    /// the same output in every format. Ignored with
+    /// [ConverterOptions::dates_as_raw_seconds].
+    pub dates_as_iso_strings: bool,
+    /// Strip a byte order mark and normalize line endings of a XML plist
+    /// before parsing it. Only applies to [Converter::from_bytes_with_options]
+    /// and [Converter::from_file_with_options].
+    pub lenient_xml: bool,
+    /// Insert a `$uid` key holding the index of the source object in
+    /// `$objects` into every decoded dictionary. Useful for debugging.
+    pub annotate_source_uids: bool,
+    /// Accept decimal strings, e.g. `"5"`, where a reference is expected. Some
+    /// broken exporters write references that way. By default such archives
+    /// are rejected with an error.
+    pub parse_string_uids: bool,
+    /// Accept a `$version` stored as a numeric string, e.g. `"100000"`. By
+    /// default such archives are rejected with an error.
+    pub lenient_version: bool,
+    /// Accept dictionaries without a `NS.keys` array whose `NS.objects` array
+    /// holds alternating keys and values. By default such dictionaries are
+    /// rejected with an error.
+    pub lenient_interleaved_dicts: bool,
+    /// Decode dictionaries into both forms side by side:
+    /// `{"$map": {...}, "$pairs": [...]}`. The map only holds the pairs with
+    /// string keys. Useful to verify the conversion. Takes precedence over
+    /// [ConverterOptions::stringify_integer_keys].
+    pub dual_dict_representation: bool,
+    /// Insert a `$refcount` key holding the number of references to the
+    /// source object into every decoded dictionary, see
+    /// [Converter::reference_count]. Useful to spot shared objects.
+    pub annotate_reference_counts: bool,
+    /// Fail with [ConverterError::CyclicReference] when an object is reached
+    /// again through its own members. By default such a reference is
+    /// replaced with a `circular reference for uid #N` string. Objects that
+    /// are merely shared by several parents are decoded either way.
+    pub reject_circular_references: bool,
+    /// Inline an object at most this many times. Further references to it
+    /// are replaced with `{"$ref": <id>}` markers, where `id` is its index in
+    /// `$objects`. Limits the output size of archives where a large object
+    /// is shared by many others. By default objects are always inlined.
+    pub max_expansions_per_object: Option<usize>,
+    /// Fail with [ConverterError::MaxDepthExceeded] if containers are nested
+    /// deeper than this. A top level container is at depth 1. See also
+    /// [RECOMMENDED_MAX_DEPTH].
+    ///
+    /// By default there's no limit, so the depth is only limited by memory.
+    /// Note that dropping an extremely deep [plist::Value] is recursive and
+    /// may overflow the stack then, so use [drop_iteratively] instead.
+    pub max_depth: Option<usize>,
+    /// Limits on the size of decoded values.
+    pub limits: DecodeLimits,
+    /// Decode data objects that hold a nested NSKeyedArchiver plist in place
+    /// of the data, using the same options. Only data that starts with a
+    /// binary plist magic or a XML prolog is tried. Data that isn't an archive
+    /// is kept as is, while errors of decoding a nested archive are returned.
+    /// The class allowlist applies to the nested archive, the string and
+    /// object transforms don't.
+    pub decode_embedded_archives: bool,
+    /// Decode `NSMutableData` objects into their class dictionary with a
+    /// `$mutable` key set to `true` instead of plain data, so they can be told
+    /// apart from `NSData` and encoded back with the right class.
+    pub preserve_mutability: bool,
+    /// Decode dictionaries whose keys are the integers `0..n` in any order
+    /// into an array ordered by key. Dictionaries with gaps between the keys
+    /// or with null values are decoded as usual. Takes precedence over
+    /// [ConverterOptions::stringify_integer_keys], but not over
+    /// [ConverterOptions::dual_dict_representation].
+    pub coalesce_integer_indexed_dicts: bool,
+    /// Decode objects that were lost in a partially recovered archive into
+    /// null values instead of failing. This covers references past the end
+    /// of `$objects`, objects whose class was replaced with `$null`, and
+    /// dictionary keys that were replaced with `$null`, whose pairs are
+    /// skipped.
+    pub lenient_null_gaps: bool,
+    /// Keep the fields that a superclass encoded into a nested `$super` or
+    /// `NS.super` object under a `super` key. By default they're merged into
+    /// the object itself, without replacing its own fields.
+    pub nest_super_objects: bool,
+    /// Replace arrays that hold a single element with the element itself.
+    /// Sets and ordered sets are left as arrays.
+    pub unwrap_singleton_arrays: bool,
+    /// Order the `key`/`value` pairs of a dictionary by a hash of the decoded
+    /// key, so that dictionaries with custom object keys come out the same
+    /// regardless of their order in the archive. By default pairs keep the
+    /// archived order.
+    pub sort_object_key_pairs: bool,
+    /// Convert every value that isn't a dictionary or an array into a string.
+    /// Data becomes a base64 encoded string and a date becomes an ISO 8601
+    /// string. `{"$ref": <id>}` markers are kept as they are.
+    pub stringify_all_scalars: bool,
+    /// Add a `$class` key holding a `{module, name}` dictionary to objects of
+    /// Swift classes, e.g. `{"module": "MyApp", "name": "Person"}` for a
+    /// `_TtC5MyApp6Person` class. By default objects of Swift classes have no
+    /// class information.
+    pub split_swift_class_module: bool,
+    /// Write integers outside of the range that JavaScript represents exactly
+    /// (`±(2^53 - 1)`) as strings in `Converter::decode_to_json`. By default
+    /// they're written as numbers.
+    pub large_ints_as_strings: bool,
+}
+
+/// State that is carried through a single decoding pass.
+#[derive(Default)]
+struct DecodeState {
+    /// References of the objects that are currently being decoded.
+    parents: HashSet<u64>,
+    /// Decoded containers keyed by their object reference. Only used by
+    /// [Converter::decode_preserving_uids].
+    shared: Option<BTreeMap<u64, Value>>,
+    /// Number of circular references that have been encountered so far.
+    circular_references: usize,
+    /// Number of times each object has been inlined, see
+    /// [ConverterOptions::max_expansions_per_object].
+    expansions: HashMap<u64, usize>,
+    /// The deepest level of nested containers reached so far, see
+    /// [ConverterOptions::max_depth].
+    deepest: usize,
+    /// Errors of members that were replaced with a placeholder. Only used by
+    /// [Converter::decode_lossy].
+    recovered_errors: Option<Vec<ConverterError>>,
+    /// Overrides [Converter::set_dict_style] for this pass.
+    dict_style: Option<DictStyle>,
 }
 
 impl Converter {
     /// Creates a new converter for a [plist::Value]. It should have a
     /// NSKeyedArchiver plist structure.
     pub fn new(plist: Value) -> Result<Self, ConverterError> {
+        Self::new_with_options(plist, ConverterOptions::default())
+    }
+
+    /// Creates a new converter for a [plist::Value] with the given options.
+    /// It should have a NSKeyedArchiver plist structure, unless
+    /// [ConverterOptions::try_passthrough] is set.
+    pub fn new_with_options(
+        plist: Value,
+        options: ConverterOptions,
+    ) -> Result<Self, ConverterError> {
+        let original = options.try_passthrough.then(|| plist.clone());
+        match Self::parse_archive(plist, &options) {
+            Ok((objects, top, top_is_array)) => {
+                let mut converter = Self::from_parts(objects, top, options, None);
+                converter.top_is_array = top_is_array;
+                Ok(converter)
+            }
+            Err(_) if original.is_some() => Ok(Self::from_parts(
+                Vec::new(),
+                Dictionary::new(),
+                options,
+                original,
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn from_parts(
+        objects: Vec<Value>,
+        top: Dictionary,
+        options: ConverterOptions,
+        passthrough: Option<Value>,
+    ) -> Self {
+        let reference_counts = reference_counts(&objects, &top);
+        Self {
+            objects,
+            top,
+            options,
+            passthrough,
+            string_transform: None,
+            object_transform: None,
+            class_allowlist: None,
+            reserved_keys: ReservedKeys::default(),
+            dict_style: DictStyle::default(),
+            top_is_array: false,
+            reference_counts,
+            cache: Mutex::new(HashMap::new()),
+            warning_sink: None,
+        }
+    }
+
+    /// Parses the header of an archive. Returns its objects, its top level
+    /// keys and whether those were stored as an array.
+    fn parse_archive(
+        plist: Value,
+        options: &ConverterOptions,
+    ) -> Result<(Vec<Value>, Dictionary, bool), ConverterError> {
         let Some(mut dict) = plist.into_dictionary() else {
             return Err(ConverterError::WrongValueType("root", "Dictionary"));
         };
@@ -88,7 +536,11 @@ impl Converter {
 
         // Check $version key
         let version_key = Self::get_header_key(&mut dict, VERSION_KEY_NAME)?;
-        let Some(version_num) = version_key.as_unsigned_integer() else {
+        let version_num = match &version_key {
+            Value::String(version) if options.lenient_version => version.trim().parse().ok(),
+            version_key => version_key.as_unsigned_integer(),
+        };
+        let Some(version_num) = version_num else {
             return Err(ConverterError::WrongValueType(VERSION_KEY_NAME, "Number"));
         };
 
@@ -98,8 +550,18 @@ impl Converter {
 
         // Check $top key
         let top_key = Self::get_header_key(&mut dict, TOP_KEY_NAME)?;
-        let Some(top) = top_key.to_owned().into_dictionary() else {
-            return Err(ConverterError::WrongValueType(TOP_KEY_NAME, "Dictionary"));
+        let (top, top_is_array) = match top_key {
+            Value::Dictionary(top) => (top, false),
+            // Array elements are keyed by their indices
+            Value::Array(elements) if options.lenient_top => (
+                elements
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, element)| (index.to_string(), element))
+                    .collect(),
+                true,
+            ),
+            _ => return Err(ConverterError::WrongValueType(TOP_KEY_NAME, "Dictionary")),
         };
 
         // Check $objects key
@@ -107,72 +569,466 @@ impl Converter {
         let Some(objects) = objects_key.into_array() else {
             return Err(ConverterError::WrongValueType(OBJECTS_KEY_NAME, "Array"));
         };
+        // `$objects` may be empty if `$top` is empty too, so the `$null`
+        // sentinel at index 0 isn't required. References to index 0 are
+        // treated as `$null` without looking it up.
 
-        Ok(Self {
-            objects,
-            top,
-            treat_all_as_classes: false,
-            leave_null_values: false,
-        })
+        Ok((objects, top, top_is_array))
     }
 
     /// Reads a plist file and creates a new converter for it. It should have a
     /// NSKeyedArchiver plist structure.
     pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, ConverterError> {
+        Self::from_file_with_options(path, ConverterOptions::default())
+    }
+
+    /// Reads a plist file and creates a new converter for it with the given
+    /// options. See [Converter::new_with_options].
+    pub fn from_file_with_options<P: AsRef<std::path::Path>>(
+        path: P,
+        options: ConverterOptions,
+    ) -> Result<Self, ConverterError> {
+        if options.lenient_xml {
+            let bytes =
+                std::fs::read(path).map_err(|e| ConverterError::PlistError(e.to_string()))?;
+            return Self::from_bytes_with_options(&bytes, options);
+        }
         let val: Value = plist::from_file(path)?;
-        Self::new(val)
+        Self::new_with_options(val, options)
     }
 
     /// Reads a plist from a byte slice and creates a new converter for it.
     /// It should have a NSKeyedArchiver plist structure.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, ConverterError> {
-        let val: Value = plist::from_bytes(bytes)?;
-        Self::new(val)
+        Self::from_bytes_with_options(bytes, ConverterOptions::default())
+    }
+
+    /// Reads a plist from a byte slice and creates a new converter for it
+    /// with the given options. See [Converter::new_with_options].
+    pub fn from_bytes_with_options(
+        bytes: &[u8],
+        options: ConverterOptions,
+    ) -> Result<Self, ConverterError> {
+        let val: Value = match options.lenient_xml {
+            true => plist::from_bytes(&normalize_xml(bytes))?,
+            false => plist::from_bytes(bytes)?,
+        };
+        Self::new_with_options(val, options)
+    }
+
+    /// Reads a plist framed by a 4-byte big-endian length prefix, as used by
+    /// some device protocols, and creates a new converter for it. The prefix
+    /// must match the length of the rest of the bytes.
+    pub fn from_length_prefixed_bytes(bytes: &[u8]) -> Result<Self, ConverterError> {
+        let Some((prefix, payload)) = bytes.split_first_chunk::<4>() else {
+            return Err(ConverterError::MissingLengthPrefix);
+        };
+        let length = u32::from_be_bytes(*prefix);
+        if usize::try_from(length).ok() != Some(payload.len()) {
+            return Err(ConverterError::LengthPrefixMismatch(length, payload.len()));
+        }
+        Self::from_bytes(payload)
     }
 
     /// Reads a plist from a seekable byte stream and creates a new converter
     /// for it. It should have a NSKeyedArchiver plist structure.
     pub fn from_reader<R: std::io::Read + std::io::Seek>(
         reader: R,
+    ) -> Result<Self, ConverterError> {
+        Self::from_reader_with_options(reader, ConverterOptions::default())
+    }
+
+    /// Reads a plist from a seekable byte stream and creates a new converter
+    /// for it with the given options. See [Converter::new_with_options].
+    pub fn from_reader_with_options<R: std::io::Read + std::io::Seek>(
+        reader: R,
+        options: ConverterOptions,
     ) -> Result<Self, ConverterError> {
         let val: Value = plist::from_reader(reader)?;
-        Self::new(val)
+        Self::new_with_options(val, options)
+    }
+
+    /// Reads a plist stored in a zip archive and creates a new converter for
+    /// it. `entry_name` is the path of the plist inside the archive. It should
+    /// have a NSKeyedArchiver plist structure.
+    #[cfg(feature = "zip")]
+    pub fn from_zip_reader<R: std::io::Read + std::io::Seek>(
+        reader: R,
+        entry_name: &str,
+    ) -> Result<Self, ConverterError> {
+        use std::io::Read;
+
+        let mut archive = zip::ZipArchive::new(reader)?;
+        let mut entry = archive.by_name(entry_name)?;
+        let mut bytes = Vec::with_capacity(usize::try_from(entry.size()).unwrap_or_default());
+        entry
+            .read_to_end(&mut bytes)
+            .map_err(|e| ConverterError::ZipError(e.to_string()))?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Memory-maps a plist file and creates a new converter for it. It should
+    /// have a NSKeyedArchiver plist structure.
+    ///
+    /// The file is parsed directly from the mapping without reading it into a
+    /// buffer first. The mapping is released before this function returns.
+    ///
+    /// The file must not be modified or truncated by another process while
+    /// it's being parsed. Otherwise the parsed data may be inconsistent, or
+    /// the process may even crash.
+    #[cfg(feature = "mmap")]
+    pub fn from_mmap<P: AsRef<std::path::Path>>(path: P) -> Result<Self, ConverterError> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: the mapping is only read while parsing, and the caller is
+        // responsible for the file not being modified in the meantime
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Self::from_bytes(&mmap)
     }
 
     /// Decodes a NSKeyedArchiver encoded plist.
     ///
     /// If successful, returns a [plist::Value] representing a converted plist.
-    pub fn decode(&mut self) -> Result<Value, ConverterError> {
-        let mut dict = Dictionary::new();
-        for (key, value) in &self.top {
-            let uid = uid!(value, key.to_string());
-            //println!("-- TOP: {key} (uid={}) --", uid.get());
-            let mut parents = vec![];
-            let Some(value) = self.decode_object(&uid.clone(), &mut parents)? else {
-                return Err(ConverterError::InvalidObjectEncoding(uid.get()));
-            };
-            dict.insert(key.clone(), value);
+    ///
+    /// The nesting depth of an archive is limited by
+    /// [ConverterOptions::max_depth].
+    pub fn decode(&self) -> Result<Value, ConverterError> {
+        self.start_decoding();
+        let mut state = DecodeState::default();
+        self.decode_top(&mut state)
+    }
+
+    /// Decodes a NSKeyedArchiver encoded plist into a dictionary of its top
+    /// level keys.
+    ///
+    /// Fails with [ConverterError::WrongValueType] if the result isn't a
+    /// dictionary, which happens with [ConverterOptions::lenient_top] or
+    /// [ConverterOptions::try_passthrough].
+    pub fn decode_to_dictionary(&self) -> Result<Dictionary, ConverterError> {
+        self.start_decoding();
+        let mut state = DecodeState::default();
+        match self.decode_top(&mut state)? {
+            Value::Dictionary(dict) => Ok(dict),
+            _ => Err(ConverterError::WrongValueType("root", "Dictionary")),
+        }
+    }
+
+    /// Decodes a NSKeyedArchiver encoded plist without giving up on the
+    /// first error, to recover what's left of a partially corrupt archive.
+    ///
+    /// A dictionary key or value, an array element, a class field or a top
+    /// level value that fails to decode is replaced with a `<decode error>`
+    /// string. The errors are returned alongside the value.
+    pub fn decode_lossy(&self) -> (Value, Vec<ConverterError>) {
+        let mut state = DecodeState {
+            recovered_errors: Some(Vec::new()),
+            ..Default::default()
+        };
+        let result = self.decode_top(&mut state);
+        let mut errors = state.recovered_errors.unwrap_or_default();
+        match result {
+            Ok(value) => (value, errors),
+            Err(e) => {
+                errors.push(e);
+                let placeholder = Value::String(DECODE_ERROR_PLACEHOLDER.to_string());
+                (placeholder, errors)
+            }
+        }
+    }
+
+    /// Decodes a single top level key without decoding the rest of the
+    /// archive. Returns `None` if there's no such key.
+    ///
+    /// Only objects reachable from the key are visited. If `$top` was an
+    /// array, its elements are keyed by their indices, e.g. `"0"`.
+    pub fn decode_key(&self, key: &str) -> Result<Option<Value>, ConverterError> {
+        if let Some(passthrough) = &self.passthrough {
+            return Ok(passthrough
+                .as_dictionary()
+                .and_then(|dict| dict.get(key))
+                .cloned());
+        }
+
+        let Some(value) = self.top.get(key) else {
+            return Ok(None);
+        };
+        let uid = self.expect_uid(value, key)?;
+        self.start_decoding();
+        let mut state = DecodeState::default();
+        match self.decode_object_at(&uid, || key.to_string(), &mut state)? {
+            Some(value) => Ok(Some(value)),
+            None if self.options.leave_null_values => {
+                Ok(Some(Value::String(NULL_OBJECT_REFERENCE_NAME.to_string())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Decodes a NSKeyedArchiver encoded plist keeping the original reference
+    /// structure.
+    ///
+    /// Instead of inlining an object every time it's referenced, each object
+    /// that holds a class (including dictionaries and arrays) is decoded once
+    /// and stored in a side table. References to it are replaced with
+    /// `{"$ref": <id>}` markers, where `id` is its index in `$objects`.
+    ///
+    /// The result is a dictionary with two keys: `$top` containing the decoded
+    /// top level keys and `$objects` mapping ids to the decoded objects.
+    pub fn decode_preserving_uids(&self) -> Result<Value, ConverterError> {
+        let mut state = DecodeState {
+            shared: Some(BTreeMap::new()),
+            ..Default::default()
+        };
+        let top = self.decode_top(&mut state)?;
+
+        let mut objects = Dictionary::new();
+        for (id, mut object) in state.shared.unwrap_or_default() {
+            if self.options.stringify_all_scalars {
+                stringify_scalars(&mut object);
+            }
+            objects.insert(id.to_string(), object);
         }
+
+        let mut dict = Dictionary::new();
+        dict.insert(TOP_KEY_NAME.to_string(), top);
+        dict.insert(OBJECTS_KEY_NAME.to_string(), Value::Dictionary(objects));
         Ok(Value::Dictionary(dict))
     }
 
+    /// Decodes several objects of `$objects` independently, ignoring `$top`.
+    /// Useful for archives that hold more than one root object.
+    ///
+    /// The result has one value per root. A root that references `$null` is
+    /// decoded into a `$null` string to keep the positions.
+    pub fn decode_sub_roots(&self, roots: &[u64]) -> Result<Vec<Value>, ConverterError> {
+        self.start_decoding();
+        let mut decoded = Vec::with_capacity(roots.len());
+        for &root in roots {
+            if self.get_object(root).is_none() {
+                return Err(ConverterError::InvalidObjectReference(root));
+            }
+            let mut state = DecodeState::default();
+            let value = self
+                .decode_object(&Uid::new(root), &mut state)?
+                .unwrap_or_else(|| Value::String(NULL_OBJECT_REFERENCE_NAME.to_string()));
+            decoded.push(value);
+        }
+        Ok(decoded)
+    }
+
     /// If set to true, treats dictionaries and arrays as regular classes.
     /// A $classes key gets retained. By default those are transformed into native plist structures.
     pub fn set_treat_all_as_classes(&mut self, value: bool) {
-        self.treat_all_as_classes = value;
+        self.clear_caches();
+        self.options.treat_all_as_classes = value;
     }
 
     pub fn treat_all_as_classes(&self) -> bool {
-        self.treat_all_as_classes
+        self.options.treat_all_as_classes
     }
 
     /// If set to true, leaves `$null` values. By default they're omitted.
     pub fn set_leave_null_values(&mut self, value: bool) {
-        self.treat_all_as_classes = value;
+        self.clear_caches();
+        self.options.leave_null_values = value;
     }
 
     pub fn leave_null_values(&self) -> bool {
-        self.leave_null_values
+        self.options.leave_null_values
+    }
+
+    /// Limits how deep containers may be nested, see
+    /// [ConverterOptions::max_depth]. `None` removes the limit.
+    pub fn set_max_depth(&mut self, value: Option<usize>) {
+        self.clear_caches();
+        self.options.max_depth = value;
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        self.options.max_depth
+    }
+
+    /// If set to true, leaves references inside of objects unresolved. See
+    /// [ConverterOptions::leave_uid_references].
+    pub fn set_leave_uid_references(&mut self, value: bool) {
+        self.clear_caches();
+        self.options.leave_uid_references = value;
+    }
+
+    pub fn leave_uid_references(&self) -> bool {
+        self.options.leave_uid_references
+    }
+
+    /// Sets a function that is applied to every decoded string, e.g. to
+    /// redact or normalize them. `$null` values and names of classes are left
+    /// as is.
+    pub fn set_string_transform(
+        &mut self,
+        transform: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) {
+        self.clear_caches();
+        self.string_transform = Some(Box::new(transform));
+    }
+
+    /// Sets a function that is applied to every decoded object. It receives
+    /// the name of the object class and the decoded value, and returns a
+    /// value to use instead.
+    ///
+    /// Plain values get the names of the Foundation classes they're
+    /// archived as: `NSString`, `NSNumber`, `NSData` and `NSDate`. `$null`
+    /// values are left as is.
+    pub fn set_object_transform(
+        &mut self,
+        transform: impl Fn(&str, Value) -> Value + Send + Sync + 'static,
+    ) {
+        self.clear_caches();
+        self.object_transform = Some(Box::new(transform));
+    }
+
+    /// Sets the form that dictionaries are decoded into. Dictionaries are
+    /// decoded into arrays of `key`/`value` pairs by default.
+    /// [ConverterOptions::coalesce_integer_indexed_dicts],
+    /// [ConverterOptions::dual_dict_representation] and
+    /// [ConverterOptions::stringify_integer_keys] take precedence.
+    pub fn set_dict_style(&mut self, style: DictStyle) {
+        self.clear_caches();
+        self.dict_style = style;
+    }
+
+    /// Sets a function that receives a message whenever decoding loses or
+    /// changes data without failing, e.g. when null values are dropped or a
+    /// circular reference is replaced. A shared object that is decoded once
+    /// and reused reports its warnings once per decoding.
+    pub fn set_warning_sink(&mut self, sink: impl FnMut(&str) + Send + 'static) {
+        self.clear_caches();
+        self.warning_sink = Some(Mutex::new(Box::new(sink)));
+    }
+
+    /// Restricts decoding to objects of the given classes. Decoding fails with
+    /// [ConverterError::DisallowedClass] when any other class is encountered.
+    pub fn set_class_allowlist(&mut self, names: &[&str]) {
+        self.clear_caches();
+        self.class_allowlist = Some(names.iter().map(|name| name.to_string()).collect());
+    }
+
+    /// Sets the names of the keys that objects are built with, for archives
+    /// that follow a nonstandard schema. The keys of the decoded output stay
+    /// the same.
+    pub fn set_reserved_keys(&mut self, keys: ReservedKeys) {
+        self.clear_caches();
+        self.reserved_keys = keys;
+    }
+
+    /// Returns the options of this converter.
+    pub fn options(&self) -> &ConverterOptions {
+        &self.options
+    }
+
+    /// Returns the options of this converter for modification.
+    pub fn options_mut(&mut self) -> &mut ConverterOptions {
+        self.clear_caches();
+        &mut self.options
+    }
+
+    /// Returns an entry of `$objects` as is, without decoding it. Returns
+    /// `None` if the index is out of range or is 0, which is reserved for
+    /// `$null`.
+    pub fn raw_object(&self, uid: u64) -> Option<&Value> {
+        if uid == 0 {
+            return None;
+        }
+        self.get_object(uid)
+    }
+
+    /// Returns the class chain of an entry of `$objects` without decoding
+    /// it, e.g. `["NSMutableArray", "NSArray", "NSObject"]`.
+    pub fn class_names_for(&self, uid: u64) -> Result<Vec<&str>, ConverterError> {
+        let Some(object) = self.raw_object(uid) else {
+            return Err(ConverterError::InvalidObjectReference(uid));
+        };
+        let Some(dict) = object.as_dictionary() else {
+            return Err(ConverterError::invalid_encoding(uid));
+        };
+        self.object_class_names(uid, dict)
+    }
+
+    /// Returns an entry of `$objects`, including the `$null` one.
+    fn get_object(&self, index: u64) -> Option<&Value> {
+        // A reference beyond `usize` can't point into `$objects`, so it
+        // mustn't be truncated into a valid index
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| self.objects.get(index))
+    }
+
+    /// Returns the header of the archive without decoding any objects.
+    ///
+    /// A passthrough converter has no top level keys or objects, see
+    /// [Converter::is_passthrough].
+    pub fn metadata(&self) -> ArchiveMetadata {
+        // Archives with another archiver or version are rejected on creation
+        ArchiveMetadata {
+            archiver: ARCHIVER.to_string(),
+            version: ARCHIVER_VERSION,
+            top_keys: self.top.keys().cloned().collect(),
+            object_count: self.objects.len(),
+        }
+    }
+
+    /// Returns true if the input isn't a NSKeyedArchiver plist and is returned
+    /// unchanged when decoding. See [ConverterOptions::try_passthrough].
+    pub fn is_passthrough(&self) -> bool {
+        self.passthrough.is_some()
+    }
+
+    /// Clears internal caches of decoded data, so that a converter which is
+    /// kept around reflects changed options on its next decode.
+    ///
+    /// Setters of this converter and [Converter::options_mut] clear the
+    /// caches automatically.
+    pub fn clear_caches(&mut self) {
+        self.cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Prepares the caches for a new decoding pass. With a warning sink they
+    /// are cleared, so the warnings of cached objects are reported again.
+    fn start_decoding(&self) {
+        if self.warning_sink.is_some() {
+            self.lock_cache().clear();
+        }
+    }
+
+    fn decode_top(&self, state: &mut DecodeState) -> Result<Value, ConverterError> {
+        if let Some(passthrough) = &self.passthrough {
+            return Ok(passthrough.clone());
+        }
+
+        let mut dict = Dictionary::new();
+        for (key, value) in &self.top {
+            let uid = self.expect_uid(value, key)?;
+            //println!("-- TOP: {key} (uid={}) --", uid.get());
+            match self.decode_object_at(&uid, || key.clone(), state)? {
+                Some(value) => {
+                    dict.insert(key.clone(), value);
+                }
+                // A top level key may map to `$null`
+                None if self.options.leave_null_values => {
+                    dict.insert(
+                        key.clone(),
+                        Value::String(NULL_OBJECT_REFERENCE_NAME.to_string()),
+                    );
+                }
+                None => {}
+            }
+        }
+        if self.top_is_array {
+            return Ok(Value::Array(
+                dict.into_iter().map(|(_, value)| value).collect(),
+            ));
+        }
+        Ok(Value::Dictionary(dict))
     }
 
     fn get_header_key(dict: &mut Dictionary, key: &'static str) -> Result<Value, ConverterError> {
@@ -185,117 +1041,623 @@ impl Converter {
     fn decode_object(
         &self,
         uid: &Uid,
-        parents: &mut Vec<Uid>,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        // Scalars are converted once the whole tree of a root is decoded, see
+        // [ConverterOptions::stringify_all_scalars]
+        let is_root = state.parents.is_empty();
+        let mut value = with_stack_guard(|| self.decode_object_unguarded(uid, state))?;
+        if let Some(value) = &mut value {
+            if is_root && self.options.stringify_all_scalars {
+                stringify_scalars(value);
+            }
+        }
+        Ok(value)
+    }
+
+    /// Decodes an object that is a member of another one, e.g. a value of a
+    /// dictionary. Its path segment is added to errors. When decoding lossily,
+    /// a member that fails is replaced with a placeholder instead.
+    fn decode_object_at(
+        &self,
+        uid: &Uid,
+        segment: impl FnOnce() -> String,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        let result = self.within(segment, state, |state| self.decode_object(uid, state));
+        match (result, &mut state.recovered_errors) {
+            (Err(e), Some(errors)) => {
+                errors.push(e);
+                Ok(Some(Value::String(DECODE_ERROR_PLACEHOLDER.to_string())))
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Runs `decode` for a part of an object and adds its path segment to the
+    /// errors it returns or recovers from.
+    fn within<T>(
+        &self,
+        segment: impl FnOnce() -> String,
+        state: &mut DecodeState,
+        decode: impl FnOnce(&mut DecodeState) -> Result<T, ConverterError>,
+    ) -> Result<T, ConverterError> {
+        let recovered = state.recovered_errors.as_ref().map_or(0, Vec::len);
+        match decode(state) {
+            Ok(value) => {
+                if let Some(errors) = &mut state.recovered_errors {
+                    if errors.len() > recovered {
+                        let segment = segment();
+                        for e in &mut errors[recovered..] {
+                            e.prepend_path(&segment);
+                        }
+                    }
+                }
+                Ok(value)
+            }
+            Err(e) => Err(e.at(&segment())),
+        }
+    }
+
+    fn decode_object_unguarded(
+        &self,
+        uid: &Uid,
+        state: &mut DecodeState,
     ) -> Result<Option<Value>, ConverterError> {
         let object_ref = uid.get();
 
+        // Only the roots are decoded, see [ConverterOptions::leave_uid_references]
+        if self.options.leave_uid_references && !state.parents.is_empty() {
+            return Ok(Some(Value::Uid(*uid)));
+        }
+
         if object_ref == 0 {
-            return Ok(None);
+            return Ok(self
+                .options
+                .leave_null_values
+                .then(|| Value::String(NULL_OBJECT_REFERENCE_NAME.to_string())));
+        }
+
+        if let Some(shared) = &state.shared {
+            if shared.contains_key(&object_ref) {
+                return Ok(Some(Self::reference_marker(object_ref)));
+            }
+        }
+
+        if let Some(max_expansions) = self.options.max_expansions_per_object {
+            let is_container = self
+                .get_object(object_ref)
+                .is_some_and(|object| self.is_container(object));
+            if is_container && !state.parents.contains(&object_ref) {
+                let expansions = state.expansions.entry(object_ref).or_insert(0);
+                if *expansions >= max_expansions {
+                    return Ok(Some(Self::reference_marker(object_ref)));
+                }
+                *expansions += 1;
+            }
         }
 
-        if parents.contains(uid) {
+        // Decoded containers are cached, since shared objects may be
+        // referenced many times. The side table takes care of them instead
+        // when it's used
+        if self.is_cache_enabled(state) {
+            let cached = self.lock_cache().get(&object_ref).cloned();
+            if let Some((cached, levels)) = cached {
+                self.enter_levels(state, levels)?;
+                return Ok(cached);
+            }
+        }
+
+        if state.parents.contains(&object_ref) {
+            if self.options.reject_circular_references {
+                return Err(ConverterError::CyclicReference(object_ref));
+            }
+            state.circular_references += 1;
             //println!("circular reference detected");
+            self.warn(|| format!("Replaced a circular reference to object #{object_ref}"));
             return Ok(Some(plist::Value::String(format!(
                 "circular reference for uid #{}",
                 uid.get()
             ))));
         }
-        parents.push(*uid);
 
-        let Some(dereferenced_object) = self.objects.get(object_ref as usize) else {
+        // An object may reference any entry of `$objects`, including ones
+        // that come after it, so no order is assumed here
+        let Some(dereferenced_object) = self.get_object(object_ref) else {
+            if self.options.lenient_null_gaps {
+                self.warn(|| format!("Replaced a missing object #{object_ref} with null"));
+                return Ok(None);
+            }
             return Err(ConverterError::InvalidObjectReference(object_ref));
         };
+        if self.options.lenient_null_gaps && self.has_lost_class(dereferenced_object) {
+            self.warn(|| format!("Replaced object #{object_ref} of a lost class with null"));
+            return Ok(None);
+        }
 
         if let Some(s) = dereferenced_object.as_string() {
-            if s == NULL_OBJECT_REFERENCE_NAME && !self.leave_null_values {
+            if s == NULL_OBJECT_REFERENCE_NAME && !self.options.leave_null_values {
                 return Ok(None);
             }
         }
 
-        let mut result = None;
-        if Self::is_container(dereferenced_object) {
-            //println!("decode_object: dereferenced_object (uid={object_ref}) is a container");
-            let Some(dict) = dereferenced_object.as_dictionary() else {
-                return Err(ConverterError::InvalidObjectEncoding(object_ref));
-            };
+        if !self.is_container(dereferenced_object) {
+            //println!("decode_object: dereferenced_object (uid={object_ref}) is NOT a container. Return {:?}", dereferenced_object);
+            if dereferenced_object.as_string() == Some(NULL_OBJECT_REFERENCE_NAME) {
+                return Ok(Some(dereferenced_object.clone()));
+            }
+            if let Some(nested) = self.decode_embedded_archive(dereferenced_object)? {
+                return Ok(Some(nested));
+            }
+            let value = self.transform_string(dereferenced_object.clone());
+            return Ok(Some(
+                self.transform_object(scalar_class_name(&value), value),
+            ));
+        }
 
-            let Some(class_reference_val) = dict.get("$class") else {
-                return Err(ConverterError::InvalidObjectEncoding(object_ref));
-            };
-            let Some(class_reference) = class_reference_val.as_uid() else {
-                return Err(ConverterError::InvalidClassReference(format!(
-                    "{:?}",
-                    class_reference_val
-                )));
-            };
+        // Reserve a slot in the side table before decoding, so references
+        // back to this object become markers too
+        if let Some(shared) = &mut state.shared {
+            shared.insert(object_ref, Value::Dictionary(Dictionary::new()));
+        }
 
-            let class_names = self.get_class_names(class_reference)?;
-            let mut found = false;
-            for name in class_names {
-                if found {
-                    break;
-                }
-                result = if !self.treat_all_as_classes {
-                    match name {
-                        "NSMutableDictionary" | "NSDictionary" => {
-                            found = true;
-                            //println!("decode_object: Decoding dictionary (uid={})", object_ref);
-                            Some(self.decode_dict(object_ref, dict, parents)?)
-                        }
-                        "NSMutableArray" | "NSArray" => {
-                            found = true;
-                            //println!("decode_object: Decoding array (uid={})", object_ref);
-                            Some(self.decode_array(object_ref, dict, parents)?)
-                        }
-                        _ => {
-                            found = true;
-                            //println!("decode_object: Decoding basic class (uid={})", object_ref);
-                            Some(self.decode_custom_class(object_ref, dict, parents)?)
-                        }
-                    }
-                } else {
-                    Some(self.decode_custom_class(object_ref, dict, parents)?)
-                }
+        let circular_references = state.circular_references;
+        self.enter_levels(state, 1)?;
+        let depth = state.parents.len() + 1;
+        let deepest = std::mem::replace(&mut state.deepest, depth);
+        state.parents.insert(object_ref);
+        let result = self.decode_container(object_ref, dereferenced_object, state);
+        state.parents.remove(&object_ref);
+        let levels = state.deepest - depth + 1;
+        state.deepest = state.deepest.max(deepest);
+        let mut result = result?;
+
+        if self.options.annotate_source_uids {
+            if let Some(Value::Dictionary(dict)) = &mut result {
+                dict.insert(
+                    SOURCE_UID_KEY_NAME.to_string(),
+                    Value::Integer(object_ref.into()),
+                );
+            }
+        }
+        if self.options.annotate_reference_counts {
+            if let Some(Value::Dictionary(dict)) = &mut result {
+                dict.insert(
+                    REFERENCE_COUNT_KEY_NAME.to_string(),
+                    Value::Integer((self.reference_count(object_ref) as u64).into()),
+                );
             }
-            Ok(result)
-        } else {
-            //println!("decode_object: dereferenced_object (uid={object_ref}) is NOT a container. Return {:?}", dereferenced_object);
-            Ok(Some(dereferenced_object.clone()))
         }
-    }
 
-    fn get_class_names(&self, uid: &Uid) -> Result<Vec<&str>, ConverterError> {
-        //println!("get_class_names: uid = {}", uid.get());
+        // A result that contains circular references depends on the path it
+        // was reached by, so it can't be reused. Objects that are referenced
+        // once are never looked up again, and copying them would make deep
+        // archives quadratic
+        if self.is_cache_enabled(state)
+            && state.circular_references == circular_references
+            && self.reference_count(object_ref) > 1
+        {
+            self.lock_cache()
+                .insert(object_ref, (result.clone(), levels));
+        }
 
-        let Some(obj) = self.objects.get(uid.get() as usize) else {
-            return Err(ConverterError::InvalidObjectEncoding(uid.get()));
-        };
+        match (&mut state.shared, result) {
+            (Some(shared), Some(value)) => {
+                shared.insert(object_ref, value);
+                Ok(Some(Self::reference_marker(object_ref)))
+            }
+            (Some(shared), None) => {
+                shared.remove(&object_ref);
+                Ok(None)
+            }
+            (None, result) => Ok(result),
+        }
+    }
 
-        let Some(names) = obj
-            .as_dictionary()
-            .and_then(|dict| dict.get("$classes").and_then(|classes| classes.as_array()))
-        else {
-            return Err(ConverterError::InvalidObjectEncoding(uid.get()));
+    fn decode_container(
+        &self,
+        object_ref: u64,
+        dereferenced_object: &Value,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        //println!("decode_object: dereferenced_object (uid={object_ref}) is a container");
+        let Some(dict) = dereferenced_object.as_dictionary() else {
+            return Err(ConverterError::invalid_encoding(object_ref));
+        };
+
+        let class_names = self.object_class_names(object_ref, dict)?;
+        self.check_class_allowed(&class_names)?;
+
+        let class_name = class_names.first().copied().unwrap_or_default();
+
+        if self.options.expand_prefix_strings && dict.contains_key(PREFIX_TABLE_KEY_NAME) {
+            let value = self.decode_prefix_string(object_ref, dict)?;
+            return Ok(Some(self.transform_object(class_name, value)));
+        }
+        let mut result = None;
+        let mut found = false;
+        for name in class_names {
+            if found {
+                break;
+            }
+            result = if !self.options.treat_all_as_classes {
+                match name {
+                    // Runtime classes like `__NSCFDictionary` may appear instead
+                    // of the public ones
+                    "NSMutableDictionary" | "NSDictionary" | "__NSCFDictionary" => {
+                        found = true;
+                        //println!("decode_object: Decoding dictionary (uid={})", object_ref);
+                        Some(self.decode_dict(object_ref, dict, state)?)
+                    }
+                    "NSMutableArray" | "NSArray" | "__NSCFArray" => {
+                        found = true;
+                        //println!("decode_object: Decoding array (uid={})", object_ref);
+                        match self.decode_array(object_ref, dict, state)? {
+                            Value::Array(mut array)
+                                if self.options.unwrap_singleton_arrays && array.len() == 1 =>
+                            {
+                                array.pop()
+                            }
+                            array => Some(array),
+                        }
+                    }
+                    // Sets store their members the same way as arrays. Ordered
+                    // sets keep the order of `NS.objects`, other keys like
+                    // `NS.orderedSet` are ignored
+                    "NSMutableSet" | "NSSet" | "NSMutableOrderedSet" | "NSOrderedSet" => {
+                        found = true;
+                        Some(self.decode_array(object_ref, dict, state)?)
+                    }
+                    "NSMutableString" | "NSString" | "__NSCFString"
+                        if dict.contains_key(NS_STRING_KEY_NAME) =>
+                    {
+                        found = true;
+                        match dict.get(NS_STRING_KEY_NAME) {
+                            Some(Value::String(s)) => {
+                                Some(self.transform_string(Value::String(s.clone())))
+                            }
+                            _ => self.decode_field(dict, NS_STRING_KEY_NAME, state)?,
+                        }
+                    }
+                    "NSPointerArray" if dict.contains_key(&self.reserved_keys.objects) => {
+                        found = true;
+                        Some(self.decode_array(object_ref, dict, state)?)
+                    }
+                    "NSCache"
+                        if dict.contains_key(&self.reserved_keys.keys)
+                            && dict.contains_key(&self.reserved_keys.objects) =>
+                    {
+                        found = true;
+                        Some(self.decode_dict(object_ref, dict, state)?)
+                    }
+                    "NSValue" | "NSConcreteValue" | "NSSpecialValue" => {
+                        found = true;
+                        match self.decode_ns_value(dict, state)? {
+                            Some(value) => Some(value),
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                    "NSDate" | "NSMutableDate" => {
+                        found = true;
+                        match self.decode_date(dict) {
+                            Some(value) => Some(value),
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                    "NSAffineTransform" => {
+                        found = true;
+                        match self.decode_affine_transform(dict) {
+                            Some(value) => Some(value),
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                    "NSMutableData" if self.options.preserve_mutability => {
+                        found = true;
+                        let mut value = self.decode_custom_class(object_ref, dict, state)?;
+                        if let Value::Dictionary(dict) = &mut value {
+                            dict.insert(MUTABLE_KEY_NAME.to_string(), Value::Boolean(true));
+                        }
+                        Some(value)
+                    }
+                    "NSNumber" | "__NSCFNumber" | "__NSCFBoolean" => {
+                        found = true;
+                        match self.decode_number(dict) {
+                            Some(value) => Some(value),
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                    "NSAttributedString"
+                    | "NSMutableAttributedString"
+                    | "NSConcreteAttributedString"
+                    | "NSConcreteMutableAttributedString" => {
+                        found = true;
+                        match self.decode_attributed_string(dict, state)? {
+                            Some(value) => Some(value),
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                    "NSDecimalNumber" | "NSDecimalNumberPlaceholder" => {
+                        found = true;
+                        match self.decode_decimal_number(dict) {
+                            Some(value) => Some(value),
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                    "NSURL" => {
+                        found = true;
+                        match self.decode_url(dict, state)? {
+                            Some(value) => Some(value),
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                    "NSUUID" => {
+                        found = true;
+                        Some(self.decode_uuid(object_ref, dict)?)
+                    }
+                    "NSMutableData" | "NSData" => {
+                        found = true;
+                        match self.decode_data(dict) {
+                            Some(value) => Some(value),
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                    _ => {
+                        found = true;
+                        //println!("decode_object: Decoding basic class (uid={})", object_ref);
+                        match registry::known_class(name) {
+                            Some(known) => {
+                                Some(self.decode_known_class(object_ref, dict, known, state)?)
+                            }
+                            None if swift::is_swift_class(name) => {
+                                Some(self.decode_swift_class(object_ref, dict, state)?)
+                            }
+                            None => Some(self.decode_custom_class(object_ref, dict, state)?),
+                        }
+                    }
+                }
+            } else {
+                Some(self.decode_custom_class(object_ref, dict, state)?)
+            }
+        }
+        Ok(result.map(|value| self.transform_object(class_name, value)))
+    }
+
+    /// Returns the value of a class field, following a reference if needed.
+    fn decode_field(
+        &self,
+        dict: &Dictionary,
+        key: &str,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        match dict.get(key) {
+            Some(Value::Uid(uid)) => self.within(
+                || key.to_string(),
+                state,
+                |state| self.decode_object(uid, state),
+            ),
+            Some(value) => Ok(Some(value.clone())),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the class of an object against the allowlist.
+    /// See [Converter::set_class_allowlist].
+    fn check_class_allowed(&self, class_names: &[&str]) -> Result<(), ConverterError> {
+        let (Some(allowlist), Some(name)) = (&self.class_allowlist, class_names.first()) else {
+            return Ok(());
+        };
+        if !allowlist.iter().any(|allowed| allowed == name) {
+            return Err(ConverterError::DisallowedClass(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Returns a value as is, following a reference if needed.
+    ///
+    /// Unlike [Converter::decode_field] the result is never decoded or
+    /// transformed, so it suits values that describe an object's structure.
+    fn resolve_raw<'a>(&'a self, value: &'a Value) -> Option<&'a Value> {
+        match value {
+            Value::Uid(uid) => self.get_object(uid.get()),
+            value => Some(value),
+        }
+    }
+
+    /// Decodes a data object that holds a nested archive. Returns `None` if
+    /// it doesn't or if [ConverterOptions::decode_embedded_archives] is off.
+    /// Errors of decoding a nested archive are returned as is.
+    fn decode_embedded_archive(&self, object: &Value) -> Result<Option<Value>, ConverterError> {
+        if !self.options.decode_embedded_archives {
+            return Ok(None);
+        }
+        let Some(data) = object.as_data() else {
+            return Ok(None);
+        };
+        if !data.starts_with(BINARY_PLIST_MAGIC) && !data.starts_with(XML_PLIST_PROLOG) {
+            return Ok(None);
+        }
+        let options = ConverterOptions {
+            try_passthrough: false,
+            ..self.options.clone()
+        };
+        let Ok(mut nested) = Self::from_bytes_with_options(data, options) else {
+            return Ok(None);
+        };
+        nested.class_allowlist = self.class_allowlist.clone();
+        nested.reserved_keys = self.reserved_keys.clone();
+        nested.decode_top(&mut DecodeState::default()).map(Some)
+    }
+
+    /// Reports a warning to the sink, see [Converter::set_warning_sink]. The
+    /// message is only built if there's a sink.
+    fn warn(&self, message: impl FnOnce() -> String) {
+        if let Some(sink) = &self.warning_sink {
+            (sink.lock().unwrap_or_else(PoisonError::into_inner))(&message());
+        }
+    }
+
+    /// Locks the cache of decoded containers. A panic in a transform or a
+    /// warning sink can't leave it in an inconsistent state, so a poisoned
+    /// lock is used anyway.
+    fn lock_cache(&self) -> MutexGuard<'_, DecodeCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Applies the string transform to a decoded string.
+    /// See [Converter::set_string_transform].
+    fn transform_string(&self, value: Value) -> Value {
+        match (value, &self.string_transform) {
+            (Value::String(s), Some(transform)) => Value::String(transform(&s)),
+            (value, _) => value,
+        }
+    }
+
+    /// Applies the object transform to a decoded object.
+    /// See [Converter::set_object_transform].
+    fn transform_object(&self, class_name: &str, value: Value) -> Value {
+        match &self.object_transform {
+            Some(transform) => transform(class_name, value),
+            None => value,
+        }
+    }
+
+    /// Checks the number of elements of a collection against
+    /// [DecodeLimits::max_collection_len].
+    fn check_collection_len(&self, index: u64, len: usize) -> Result<(), ConverterError> {
+        match self.options.limits.max_collection_len {
+            Some(max_len) if len > max_len => {
+                Err(ConverterError::CollectionTooLarge { index, len })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records that `levels` nested containers are entered below the current
+    /// parents and checks them against [ConverterOptions::max_depth].
+    fn enter_levels(&self, state: &mut DecodeState, levels: usize) -> Result<(), ConverterError> {
+        let depth = state.parents.len() + levels;
+        if let Some(max_depth) = self.options.max_depth {
+            if depth > max_depth {
+                return Err(ConverterError::MaxDepthExceeded(max_depth));
+            }
+        }
+        state.deepest = state.deepest.max(depth);
+        Ok(())
+    }
+
+    /// Whether decoded containers may be reused. A cached object would hide
+    /// its members from the side table and from the expansion limit, and its
+    /// errors when decoding lossily.
+    fn is_cache_enabled(&self, state: &DecodeState) -> bool {
+        state.shared.is_none()
+            && state.recovered_errors.is_none()
+            && state.dict_style.is_none()
+            && self.options.max_expansions_per_object.is_none()
+    }
+
+    /// Creates a `{"$ref": <id>}` marker pointing at an object.
+    fn reference_marker(object_ref: u64) -> Value {
+        let mut dict = Dictionary::new();
+        dict.insert(
+            REFERENCE_KEY_NAME.to_string(),
+            Value::Integer(object_ref.into()),
+        );
+        Value::Dictionary(dict)
+    }
+
+    fn get_class_names(&self, uid: &Uid) -> Result<Vec<&str>, ConverterError> {
+        //println!("get_class_names: uid = {}", uid.get());
+
+        let Some(obj) = self.get_object(uid.get()) else {
+            return Err(ConverterError::invalid_encoding(uid.get()));
+        };
+
+        let Some(names) = obj.as_dictionary().and_then(|dict| {
+            dict.get(&self.reserved_keys.classes)
+                .and_then(|classes| classes.as_array())
+        }) else {
+            return Err(ConverterError::InvalidClassObject(uid.get()));
         };
 
         let mut vec_of_names = Vec::new();
         for name in names {
             let Some(name) = name.as_string() else {
-                return Err(ConverterError::InvalidObjectEncoding(uid.get()));
+                return Err(ConverterError::invalid_encoding(uid.get()));
             };
             vec_of_names.push(name);
         }
         Ok(vec_of_names)
     }
 
-    fn is_container(val: &Value) -> bool {
+    /// Returns the class names of an object, starting with its own class.
+    fn object_class_names<'a>(
+        &'a self,
+        object_ref: u64,
+        dict: &'a Dictionary,
+    ) -> Result<Vec<&'a str>, ConverterError> {
+        if let Some(name) = self.inline_class_name(dict) {
+            return Ok(vec![name]);
+        }
+
+        let Some(class_reference_val) = dict.get(&self.reserved_keys.class) else {
+            return Err(ConverterError::invalid_encoding(object_ref));
+        };
+        let Some(class_reference) = self.as_reference(class_reference_val) else {
+            return Err(ConverterError::InvalidClassReference(format!(
+                "{:?}",
+                class_reference_val
+            )));
+        };
+        self.get_class_names(&class_reference)
+            .map_err(|e| e.at(&self.reserved_keys.class))
+    }
+
+    /// Returns the class name that some archives store directly on an object
+    /// instead of a `$class` reference. Class objects themselves, which also
+    /// have a `$classes` key, don't count.
+    fn inline_class_name<'a>(&self, dict: &'a Dictionary) -> Option<&'a str> {
+        if dict.contains_key(&self.reserved_keys.class)
+            || dict.contains_key(&self.reserved_keys.classes)
+        {
+            return None;
+        }
+        dict.get(CLASS_NAME_KEY_NAME)?.as_string()
+    }
+
+    /// Checks if an object references a class object that was replaced with
+    /// `$null`.
+    fn has_lost_class(&self, val: &Value) -> bool {
+        val.as_dictionary()
+            .and_then(|dict| dict.get(&self.reserved_keys.class))
+            .and_then(|class| self.as_reference(class))
+            .and_then(|class| self.get_object(class.get()))
+            .is_some_and(|class| class.as_string() == Some(NULL_OBJECT_REFERENCE_NAME))
+    }
+
+    fn is_container(&self, val: &Value) -> bool {
         let Some(dict) = val.as_dictionary() else {
             return false;
         };
-        if let Some(cls) = dict.get("$class") {
-            cls.as_uid().is_some()
+        if let Some(cls) = dict.get(&self.reserved_keys.class) {
+            self.as_reference(cls).is_some()
         } else {
-            false
+            self.inline_class_name(dict).is_some()
+        }
+    }
+
+    /// Reads a reference, see [Converter::as_reference].
+    fn expect_uid(&self, value: &Value, key: &str) -> Result<Uid, ConverterError> {
+        self.as_reference(value)
+            .ok_or_else(|| ConverterError::ExpectedUIDValue(key.to_string()))
+    }
+
+    /// Reads a reference. A decimal string is accepted as well with
+    /// [ConverterOptions::parse_string_uids].
+    fn as_reference(&self, value: &Value) -> Option<Uid> {
+        match value {
+            Value::Uid(uid) => Some(*uid),
+            Value::String(s) if self.options.parse_string_uids => s.parse().ok().map(Uid::new),
+            _ => None,
         }
     }
 
@@ -303,72 +1665,182 @@ impl Converter {
         &self,
         uid: u64,
         val: &Dictionary,
-        parents: &mut Vec<Uid>,
+        state: &mut DecodeState,
     ) -> Result<Value, ConverterError> {
         let mut class_dict = Dictionary::new();
+        // Fields of superclasses, see [ConverterOptions::nest_super_objects]
+        let mut super_fields = Vec::new();
+        let inline_class_name = self.inline_class_name(val);
+        if let Some(name) = inline_class_name {
+            class_dict.insert(
+                "$classes".to_string(),
+                Value::Array(vec![Value::String(name.to_string())]),
+            );
+        }
         for (key, value) in val {
-            if key == "$class" {
+            if key == CLASS_NAME_KEY_NAME && inline_class_name.is_some() {
+                continue;
+            }
+            if *key == self.reserved_keys.class {
                 //println!("{:?}", value);
-                let Some(classes_obj) =
-                    self.decode_object(uid!(value, key.to_string()), &mut parents.clone())?
+                let class_uid = self.expect_uid(value, key)?;
+                let Some(classes_obj) = self.within(
+                    || key.clone(),
+                    state,
+                    |state| self.decode_object(&class_uid, state),
+                )?
                 else {
-                    return Err(ConverterError::InvalidObjectEncoding(uid));
+                    return Err(ConverterError::invalid_encoding(uid));
                 };
                 let Some(classes) = classes_obj
                     .as_dictionary()
-                    .and_then(|dict| dict.get("$classes"))
+                    .and_then(|dict| dict.get(&self.reserved_keys.classes))
                 else {
-                    return Err(ConverterError::InvalidObjectEncoding(uid));
+                    return Err(ConverterError::InvalidClassObject(class_uid.get()));
                 };
                 class_dict.insert("$classes".to_string(), classes.clone());
                 continue;
             }
 
             let decoded_value = match value {
-                Value::Uid(u) => self.decode_object(u, &mut parents.clone())?,
+                Value::Uid(u) => self.decode_object_at(u, || key.clone(), state)?,
                 Value::Array(arr) => {
                     let mut decoded_array = Vec::with_capacity(arr.len());
                     for val in arr {
-                        if let Ok(Some(unwrapped)) =
-                            self.decode_object(uid!(val, key.to_string()), parents)
-                        {
-                            decoded_array.push(unwrapped);
+                        match self.decode_object(&self.expect_uid(val, key)?, state) {
+                            Ok(Some(unwrapped)) => decoded_array.push(unwrapped),
+                            Ok(None) => self.warn(|| {
+                                format!("Dropped a null element of field '{key}' of object #{uid}")
+                            }),
+                            Err(e) if e.is_policy_violation() => return Err(e),
+                            Err(e) => self.warn(|| {
+                                format!("Dropped an element of field '{key}' of object #{uid}: {e}")
+                            }),
                         }
                     }
                     Some(Value::Array(decoded_array))
                 }
-                _ => Some(value.clone()),
+                _ => Some(self.transform_string(value.clone())),
             };
 
-            if let Some(v) = decoded_value {
-                class_dict.insert(key.clone(), v);
-            } else {
-                //println!("decode_basic_class: Skipping an empty key-value pair");
+            match decoded_value {
+                Some(v) if SUPER_KEY_NAMES.contains(&key.as_str()) => {
+                    match (v, self.options.nest_super_objects) {
+                        (Value::Dictionary(fields), false) => super_fields.push(fields),
+                        (v, _) => {
+                            class_dict.insert(SUPER_OUTPUT_KEY_NAME.to_string(), v);
+                        }
+                    }
+                }
+                Some(v) => {
+                    class_dict.insert(key.clone(), v);
+                }
+                None => {
+                    //println!("decode_basic_class: Skipping an empty key-value pair");
+                    self.warn(|| format!("Dropped a null field '{key}' of object #{uid}"));
+                }
+            }
+        }
+
+        for fields in super_fields {
+            for (key, value) in fields {
+                if key != "$classes" && !class_dict.contains_key(&key) {
+                    class_dict.insert(key, value);
+                }
             }
         }
         Ok(Value::Dictionary(class_dict))
     }
 
+    fn decode_prefix_string(&self, uid: u64, val: &Dictionary) -> Result<Value, ConverterError> {
+        // The table is either an inline array of references or a reference
+        // to an array object
+        let prefixes = match val.get(PREFIX_TABLE_KEY_NAME) {
+            Some(Value::Array(prefixes)) => Some(prefixes),
+            Some(Value::Uid(table_uid)) => self
+                .get_object(table_uid.get())
+                .and_then(|table| table.as_dictionary())
+                .and_then(|table| table.get(&self.reserved_keys.objects))
+                .and_then(|prefixes| prefixes.as_array()),
+            _ => None,
+        };
+        let index = val
+            .get(PREFIX_INDEX_KEY_NAME)
+            .and_then(|index| self.resolve_raw(index))
+            .and_then(|index| index.as_unsigned_integer());
+
+        let prefix = prefixes
+            .zip(index)
+            .and_then(|(prefixes, index)| prefixes.get(usize::try_from(index).ok()?))
+            .and_then(|prefix| self.resolve_raw(prefix))
+            .and_then(|prefix| prefix.as_string());
+        let suffix = val
+            .get(PREFIX_SUFFIX_KEY_NAME)
+            .and_then(|suffix| self.resolve_raw(suffix))
+            .and_then(|suffix| suffix.as_string());
+        let (Some(prefix), Some(suffix)) = (prefix, suffix) else {
+            return Err(ConverterError::invalid_encoding(uid));
+        };
+
+        Ok(self.transform_string(Value::String(format!("{prefix}{suffix}"))))
+    }
+
     fn decode_array(
         &self,
         uid: u64,
         val: &Dictionary,
-        parents: &mut Vec<Uid>,
+        state: &mut DecodeState,
     ) -> Result<Value, ConverterError> {
         //println!("decode_array: {:?}", val);
-        let Some(raw_object) = val.get("NS.objects").and_then(|objs| objs.as_array()) else {
-            return Err(ConverterError::InvalidObjectEncoding(uid));
+        let Some(raw_object) = val
+            .get(&self.reserved_keys.objects)
+            .and_then(|objs| objs.as_array())
+        else {
+            return Err(ConverterError::invalid_encoding(uid));
         };
+        self.within(
+            || self.reserved_keys.objects.clone(),
+            state,
+            |state| self.decode_array_elements(uid, raw_object, state),
+        )
+    }
+
+    /// Decodes the elements of `NS.objects`. Some encoders store jagged
+    /// arrays as nested arrays of references, those are decoded at every
+    /// level.
+    fn decode_array_elements(
+        &self,
+        uid: u64,
+        raw_object: &[Value],
+        state: &mut DecodeState,
+    ) -> Result<Value, ConverterError> {
+        with_stack_guard(|| self.decode_array_elements_unguarded(uid, raw_object, state))
+    }
+
+    fn decode_array_elements_unguarded(
+        &self,
+        uid: u64,
+        raw_object: &[Value],
+        state: &mut DecodeState,
+    ) -> Result<Value, ConverterError> {
+        self.check_collection_len(uid, raw_object.len())?;
         let mut array: Vec<Value> = Vec::with_capacity(raw_object.len());
-        for element in raw_object {
-            let decoded_value = self.decode_object(
-                uid!(element, "NS.objects".to_string()),
-                &mut parents.clone(),
-            )?;
+        for (index, element) in raw_object.iter().enumerate() {
+            let segment = || format!("[{index}]");
+            let decoded_value = match element {
+                Value::Array(nested) => Some(self.within(segment, state, |state| {
+                    self.decode_array_elements(uid, nested, state)
+                })?),
+                element => {
+                    let element_uid = self.expect_uid(element, &self.reserved_keys.objects)?;
+                    self.decode_object_at(&element_uid, segment, state)?
+                }
+            };
             if let Some(v) = decoded_value {
                 array.push(v);
             } else {
                 //println!("decode_array: Skipping an empty key-value pair");
+                self.warn(|| format!("Dropped a null element of array #{uid}"));
             }
         }
         Ok(Value::Array(array))
@@ -378,50 +1850,1995 @@ impl Converter {
         &self,
         uid: u64,
         val: &Dictionary,
-        parents: &mut Vec<Uid>,
+        state: &mut DecodeState,
     ) -> Result<Value, ConverterError> {
-        let Some(keys) = val.get("NS.keys").and_then(|keys| keys.as_array()) else {
-            return Err(ConverterError::InvalidObjectEncoding(uid));
+        let Some(objects) = val
+            .get(&self.reserved_keys.objects)
+            .and_then(|objs| objs.as_array())
+        else {
+            return Err(ConverterError::invalid_encoding(uid));
         };
-        let Some(values) = val.get("NS.objects").and_then(|objs| objs.as_array()) else {
-            return Err(ConverterError::InvalidObjectEncoding(uid));
+        let (keys, values): (Vec<&Value>, Vec<&Value>) = match val
+            .get(&self.reserved_keys.keys)
+            .and_then(|keys| keys.as_array())
+        {
+            Some(keys) => (keys.iter().collect(), objects.iter().collect()),
+            // Keys and values alternate in a single array
+            None if self.options.lenient_interleaved_dicts && objects.len() % 2 == 0 => (
+                objects.iter().step_by(2).collect(),
+                objects.iter().skip(1).step_by(2).collect(),
+            ),
+            None => return Err(ConverterError::invalid_encoding(uid)),
         };
         //println!("Decode dict, keys: {:?}", keys);
         //println!("Decode dict, values: {:?}", values);
+        self.check_collection_len(uid, keys.len())?;
+        // Paths of keys and values in the raw archive, used for errors
+        let is_interleaved = !val.contains_key(&self.reserved_keys.keys);
+        let key_path = |position: usize| match is_interleaved {
+            true => format!("{}[{}]", self.reserved_keys.objects, position * 2),
+            false => format!("{}[{position}]", self.reserved_keys.keys),
+        };
+        let value_path = |position: usize| match is_interleaved {
+            true => format!("{}[{}]", self.reserved_keys.objects, position * 2 + 1),
+            false => format!("{}[{position}]", self.reserved_keys.objects),
+        };
 
         // Decode keys and values
         let mut decoded_keys = Vec::with_capacity(keys.len());
         let mut decoded_values = Vec::with_capacity(values.len());
-        for key in keys {
-            let Some(decoded_key) =
-                self.decode_object(uid!(key, "NS.keys".to_string()), &mut parents.clone())?
-            else {
-                return Err(ConverterError::InvalidObjectEncoding(uid));
-            };
-            decoded_keys.push(decoded_key);
+        // Positions of keys that were lost, see [ConverterOptions::lenient_null_gaps]
+        let mut lost_keys = HashSet::new();
+        for (position, key) in keys.iter().enumerate() {
+            let key_uid = self.expect_uid(key, &self.reserved_keys.keys)?;
+            match self.decode_object_at(&key_uid, || key_path(position), state)? {
+                Some(decoded_key) => decoded_keys.push(decoded_key),
+                None if self.options.lenient_null_gaps => {
+                    self.warn(|| format!("Dropped a pair with a lost key of dictionary #{uid}"));
+                    lost_keys.insert(position);
+                }
+                None => return Err(ConverterError::invalid_encoding(uid)),
+            }
         }
-        for value in values {
-            let Some(decoded_value) =
-                self.decode_object(uid!(value, "NS.objects".to_string()), parents)?
-            else {
-                return Err(ConverterError::InvalidObjectEncoding(uid));
-            };
+        for (position, value) in values.iter().enumerate() {
+            if lost_keys.contains(&position) {
+                continue;
+            }
+            // A value may be `$null`
+            let value_uid = self.expect_uid(value, &self.reserved_keys.objects)?;
+            let decoded_value =
+                match self.decode_object_at(&value_uid, || value_path(position), state)? {
+                    None if self.options.leave_null_values => {
+                        Some(Value::String(NULL_OBJECT_REFERENCE_NAME.to_string()))
+                    }
+                    None => {
+                        self.warn(|| {
+                            format!("Dropped a pair with a null value of dictionary #{uid}")
+                        });
+                        None
+                    }
+                    decoded_value => decoded_value,
+                };
             decoded_values.push(decoded_value);
         }
+        if decoded_keys.len() != decoded_values.len() {
+            return Err(ConverterError::invalid_encoding(uid));
+        }
 
         //println!("decode_dict: decoded_keys = {:?}", decoded_keys);
         //println!("decode_dict: decoded_values = {:?}", decoded_keys);
 
+        if self.options.coalesce_integer_indexed_dicts && !self.options.dual_dict_representation {
+            if let Some(array) = integer_indexed_array(&decoded_keys, &mut decoded_values) {
+                return Ok(Value::Array(array));
+            }
+        }
+
+        if self.options.stringify_integer_keys
+            && !self.options.dual_dict_representation
+            && !decoded_keys.is_empty()
+            && decoded_keys
+                .iter()
+                .all(|key| key.as_signed_integer().is_some())
+        {
+            let mut dict = Dictionary::new();
+            for (key, value) in decoded_keys.into_iter().zip(decoded_values) {
+                let (Some(key), Some(value)) = (key.as_signed_integer(), value) else {
+                    continue;
+                };
+                dict.insert(key.to_string(), value);
+            }
+            return Ok(Value::Dictionary(dict));
+        }
+
+        let is_map = match state.dict_style.unwrap_or(self.dict_style) {
+            _ if self.options.dual_dict_representation => false,
+            DictStyle::KeyValuePairs => false,
+            DictStyle::Map => true,
+            DictStyle::Auto => {
+                let mut seen = HashSet::new();
+                decoded_keys
+                    .iter()
+                    .zip(&decoded_values)
+                    .filter(|(_, value)| value.is_some())
+                    .all(|(key, _)| key.as_string().is_some_and(|key| seen.insert(key)))
+            }
+        };
+        if is_map {
+            let mut dict = Dictionary::new();
+            for (key, value) in decoded_keys.into_iter().zip(decoded_values) {
+                let Some(value) = value else {
+                    continue;
+                };
+                let key = match key {
+                    Value::String(key) => key,
+                    Value::Integer(key) => key.to_string(),
+                    Value::Real(key) => key.to_string(),
+                    Value::Boolean(key) => key.to_string(),
+                    _ => {
+                        self.warn(|| {
+                            format!("Dropped a pair with a non-scalar key of dictionary #{uid}")
+                        });
+                        continue;
+                    }
+                };
+                if dict.insert(key, value).is_some() {
+                    self.warn(|| {
+                        format!("Replaced a pair with a duplicate key of dictionary #{uid}")
+                    });
+                }
+            }
+            return Ok(Value::Dictionary(dict));
+        }
+
         // A dictionary key can be a number, a string or a custom object.
         // So we rather make an a array of dictionaries
-        let mut array_of_dicts = Vec::with_capacity(decoded_keys.len());
-        while !decoded_keys.is_empty() {
+        let mut pairs: Vec<(Value, Value)> = decoded_keys
+            .into_iter()
+            .zip(decoded_values)
+            // Skip pairs with a null value
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        if self.options.sort_object_key_pairs {
+            pairs.sort_by_cached_key(|(key, _)| canonical_hash(key));
+        }
+        let mut array_of_dicts = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
             let mut dict: Dictionary = Dictionary::new();
-            dict.insert("key".to_string(), decoded_keys.remove(0));
-            dict.insert("value".to_string(), decoded_values.remove(0));
+            dict.insert("key".to_string(), key);
+            dict.insert("value".to_string(), value);
             array_of_dicts.push(Value::Dictionary(dict));
         }
 
+        if self.options.dual_dict_representation {
+            let mut map = Dictionary::new();
+            for pair in array_of_dicts
+                .iter()
+                .filter_map(|pair| pair.as_dictionary())
+            {
+                if let (Some(Value::String(key)), Some(value)) =
+                    (pair.get("key"), pair.get("value"))
+                {
+                    map.insert(key.clone(), value.clone());
+                }
+            }
+            let mut dual = Dictionary::new();
+            dual.insert(DICT_MAP_KEY_NAME.to_string(), Value::Dictionary(map));
+            dual.insert(
+                DICT_PAIRS_KEY_NAME.to_string(),
+                Value::Array(array_of_dicts),
+            );
+            return Ok(Value::Dictionary(dual));
+        }
+
         Ok(Value::Array(array_of_dicts))
     }
 }
+
+/// Converts every value nested in `value` that isn't a dictionary or an array
+/// into a string, leaving reference markers as they are.
+pub(crate) fn stringify_scalars(value: &mut Value) {
+    with_stack_guard(|| stringify_scalars_unguarded(value))
+}
+
+fn stringify_scalars_unguarded(value: &mut Value) {
+    let s = match value {
+        Value::Array(array) => {
+            array.iter_mut().for_each(stringify_scalars);
+            return;
+        }
+        Value::Dictionary(dict) => {
+            if !(dict.len() == 1 && dict.contains_key(REFERENCE_KEY_NAME)) {
+                dict.values_mut().for_each(stringify_scalars);
+            }
+            return;
+        }
+        Value::String(_) => return,
+        Value::Boolean(b) => b.to_string(),
+        Value::Data(data) => base64::engine::general_purpose::STANDARD.encode(data),
+        Value::Date(date) => date.to_xml_format(),
+        Value::Real(real) => real.to_string(),
+        Value::Integer(integer) => integer.to_string(),
+        Value::Uid(uid) => uid.get().to_string(),
+        _ => return,
+    };
+    *value = Value::String(s);
+}
+
+/// Hashes a value with FNV-1a, which unlike the hasher of [HashMap] gives the
+/// same result on every run and platform. Dictionaries are hashed with their
+/// keys sorted.
+fn canonical_hash(value: &Value) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    hash_value(value, &mut hash);
+    hash
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn hash_bytes(bytes: &[u8], hash: &mut u64) {
+    for byte in bytes {
+        *hash = (*hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME);
+    }
+}
+
+/// Hashes a length followed by the bytes, so that adjacent values can't run
+/// into each other.
+fn hash_prefixed_bytes(bytes: &[u8], hash: &mut u64) {
+    hash_bytes(&(bytes.len() as u64).to_le_bytes(), hash);
+    hash_bytes(bytes, hash);
+}
+
+fn hash_value(value: &Value, hash: &mut u64) {
+    with_stack_guard(|| hash_value_unguarded(value, hash))
+}
+
+fn hash_value_unguarded(value: &Value, hash: &mut u64) {
+    // A tag keeps values of different types with the same contents apart
+    match value {
+        Value::Array(array) => {
+            hash_bytes(&[0], hash);
+            hash_bytes(&(array.len() as u64).to_le_bytes(), hash);
+            array.iter().for_each(|element| hash_value(element, hash));
+        }
+        Value::Dictionary(dict) => {
+            hash_bytes(&[1], hash);
+            hash_bytes(&(dict.len() as u64).to_le_bytes(), hash);
+            let mut entries: Vec<_> = dict.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            for (key, value) in entries {
+                hash_prefixed_bytes(key.as_bytes(), hash);
+                hash_value(value, hash);
+            }
+        }
+        Value::Boolean(b) => hash_bytes(&[2, u8::from(*b)], hash),
+        Value::Data(data) => {
+            hash_bytes(&[3], hash);
+            hash_prefixed_bytes(data, hash);
+        }
+        Value::Date(date) => {
+            hash_bytes(&[4], hash);
+            hash_prefixed_bytes(date.to_xml_format().as_bytes(), hash);
+        }
+        Value::Real(real) => {
+            hash_bytes(&[5], hash);
+            hash_bytes(&real.to_bits().to_le_bytes(), hash);
+        }
+        Value::Integer(integer) => {
+            hash_bytes(&[6], hash);
+            hash_prefixed_bytes(integer.to_string().as_bytes(), hash);
+        }
+        Value::String(s) => {
+            hash_bytes(&[7], hash);
+            hash_prefixed_bytes(s.as_bytes(), hash);
+        }
+        Value::Uid(uid) => {
+            hash_bytes(&[8], hash);
+            hash_bytes(&uid.get().to_le_bytes(), hash);
+        }
+        _ => hash_bytes(&[9], hash),
+    }
+}
+
+/// Orders the values of a dictionary by its keys if they're the integers
+/// `0..n` and all values are present. The values are taken out in that case.
+fn integer_indexed_array(keys: &[Value], values: &mut [Option<Value>]) -> Option<Vec<Value>> {
+    if keys.is_empty() {
+        return None;
+    }
+    let mut slots: Vec<Option<usize>> = vec![None; keys.len()];
+    for (position, key) in keys.iter().enumerate() {
+        let index = usize::try_from(key.as_unsigned_integer()?).ok()?;
+        let slot = slots.get_mut(index)?;
+        if slot.is_some() || values[position].is_none() {
+            return None;
+        }
+        *slot = Some(position);
+    }
+    // Every slot is filled, since there are as many keys as slots
+    slots
+        .into_iter()
+        .map(|position| position.and_then(|position| values[position].take()))
+        .collect()
+}
+
+/// Counts references to each object from other objects and from `$top`.
+fn reference_counts(objects: &[Value], top: &Dictionary) -> HashMap<u64, usize> {
+    let mut counts = HashMap::new();
+    let references = objects
+        .iter()
+        .chain(top.values())
+        .flat_map(graph::object_references);
+    for reference in references {
+        *counts.entry(reference).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Space that must be left on the stack before a recursive call.
+const STACK_RED_ZONE: usize = 64 * 1024;
+/// Size of a new stack segment that is allocated when the space runs out.
+const STACK_SEGMENT_SIZE: usize = 1024 * 1024;
+
+/// Runs a recursive step, moving to a new stack segment on the heap when the
+/// current one runs out. This way deeply nested archives are only limited by
+/// memory.
+pub(crate) fn with_stack_guard<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, f)
+}
+
+/// Drops a value without recursion, since the default drop of a deeply
+/// nested value may overflow the stack. Use it to dispose of values decoded
+/// without [ConverterOptions::max_depth].
+pub fn drop_iteratively(value: Value) {
+    let mut values = vec![value];
+    while let Some(value) = values.pop() {
+        match value {
+            Value::Array(array) => values.extend(array),
+            Value::Dictionary(dict) => values.extend(dict.into_iter().map(|(_, value)| value)),
+            _ => {}
+        }
+    }
+}
+
+/// Returns the name of the Foundation class a plain value is archived as.
+fn scalar_class_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "NSString",
+        Value::Data(_) => "NSData",
+        Value::Date(_) => "NSDate",
+        _ => "NSNumber",
+    }
+}
+
+/// Prepares a possibly mangled XML plist for parsing: strips a UTF-8 byte
+/// order mark and leading whitespace, and converts CR/LF and CR line endings
+/// into LF. Binary plists are returned unchanged.
+fn normalize_xml(bytes: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+    let stripped = bytes.strip_prefix(BOM).unwrap_or(bytes).trim_ascii_start();
+    if !stripped.starts_with(b"<") {
+        return bytes.into();
+    }
+
+    let mut normalized = Vec::with_capacity(stripped.len());
+    let mut iter = stripped.iter().copied().peekable();
+    while let Some(byte) = iter.next() {
+        if byte == b'\r' {
+            iter.next_if_eq(&b'\n');
+            normalized.push(b'\n');
+        } else {
+            normalized.push(byte);
+        }
+    }
+    normalized.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn decode_preserving_uids_stores_shared_objects_once() {
+        let converter = converter(vec![
+            string("$null"),
+            array(3, &[2, 2]),
+            object(4, vec![("name", uid(5))]),
+            class(&["NSArray", "NSObject"]),
+            class(&["Person", "NSObject"]),
+            string("Alice"),
+        ]);
+        let decoded = converter.decode_preserving_uids().unwrap();
+        let decoded = decoded.as_dictionary().unwrap();
+
+        let marker = |id: i64| dict(vec![("$ref", int(id))]);
+        assert_eq!(decoded["$top"], dict(vec![("root", marker(1))]),);
+        let objects = decoded["$objects"].as_dictionary().unwrap();
+        assert_eq!(objects.keys().collect::<Vec<_>>(), ["1", "2"]);
+        assert_eq!(objects["1"], Value::Array(vec![marker(2), marker(2)]));
+        assert_eq!(
+            objects["2"],
+            dict(vec![
+                (
+                    "$classes",
+                    Value::Array(vec![string("Person"), string("NSObject")])
+                ),
+                ("name", string("Alice")),
+            ])
+        );
+    }
+
+    #[test]
+    fn class_reference_to_a_string_is_an_invalid_class_object() {
+        let converter = converter(vec![
+            string("$null"),
+            object(2, vec![("name", uid(3))]),
+            string("NotAClass"),
+            string("Alice"),
+        ]);
+        assert!(matches!(
+            converter.decode(),
+            Err(ConverterError::InvalidClassObject(2))
+        ));
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn from_zip_reader_decodes_the_named_entry() {
+        use std::io::{Cursor, Write};
+
+        let plist = archive(
+            vec![("root", uid(1))],
+            vec![string("$null"), string("Hello")],
+        );
+        let mut bytes = Vec::new();
+        plist::to_writer_binary(&mut bytes, &plist).unwrap();
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("readme.txt", options).unwrap();
+        zip.write_all(b"Not an archive").unwrap();
+        zip.start_file("backup/archive.plist", options).unwrap();
+        zip.write_all(&bytes).unwrap();
+        let zipped = zip.finish().unwrap();
+
+        let converter =
+            Converter::from_zip_reader(Cursor::new(zipped.into_inner()), "backup/archive.plist")
+                .unwrap();
+        assert_eq!(
+            converter.decode().unwrap(),
+            dict(vec![("root", string("Hello"))])
+        );
+        assert!(matches!(
+            Converter::from_zip_reader(Cursor::new(Vec::new()), "archive.plist"),
+            Err(ConverterError::ZipError(_))
+        ));
+    }
+
+    #[test]
+    fn cached_objects_are_reused_until_caches_are_cleared() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let mut converter = converter(vec![
+            string("$null"),
+            array(4, &[2, 2]),
+            object(3, vec![("name", uid(5))]),
+            class(&["Person", "NSObject"]),
+            class(&["NSArray", "NSObject"]),
+            string("Alice"),
+        ]);
+        let uppercase = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&uppercase);
+        converter.set_string_transform(move |s| match flag.load(Ordering::Relaxed) {
+            true => s.to_uppercase(),
+            false => s.to_string(),
+        });
+        let person = |name| {
+            dict(vec![
+                (
+                    "$classes",
+                    Value::Array(vec![string("Person"), string("NSObject")]),
+                ),
+                ("name", string(name)),
+            ])
+        };
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![person("Alice"); 2])
+        );
+
+        // The transform changed behind the converter's back, so the cached
+        // objects are stale until the caches are cleared
+        uppercase.store(true, Ordering::Relaxed);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![person("Alice"); 2])
+        );
+        converter.clear_caches();
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![person("ALICE"); 2])
+        );
+
+        // Changing options clears the caches automatically
+        converter.options_mut().treat_all_as_classes = true;
+        let decoded = decode_root(&converter);
+        let decoded = decoded.as_dictionary().unwrap();
+        assert_eq!(
+            decoded["NS.objects"],
+            Value::Array(vec![person("ALICE"); 2])
+        );
+        assert!(decoded.contains_key("$classes"));
+    }
+
+    #[test]
+    fn plain_plist_passes_through_with_the_option() {
+        let plain = dict(vec![("title", string("Hello")), ("count", int(2))]);
+        assert!(matches!(
+            Converter::new(plain.clone()),
+            Err(ConverterError::MissingHeaderKey(_))
+        ));
+
+        let options = ConverterOptions {
+            try_passthrough: true,
+            ..Default::default()
+        };
+        let converter = Converter::new_with_options(plain.clone(), options).unwrap();
+        assert!(converter.is_passthrough());
+        assert_eq!(converter.decode().unwrap(), plain);
+    }
+
+    #[test]
+    fn forward_references_decode() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3]),
+            class(&["NSArray", "NSObject"]),
+            object(6, vec![("name", uid(7)), ("age", uid(5))]),
+            string("unused"),
+            int(30),
+            class(&["Person", "NSObject"]),
+            string("Alice"),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![dict(vec![
+                (
+                    "$classes",
+                    Value::Array(vec![string("Person"), string("NSObject")])
+                ),
+                ("name", string("Alice")),
+                ("age", int(30)),
+            ])])
+        );
+    }
+
+    #[test]
+    fn from_bytes_with_options_applies_the_options() {
+        let plist = archive(
+            vec![("root", uid(1))],
+            vec![
+                string("$null"),
+                dictionary(2, &[3], &[0]),
+                class(&["NSDictionary", "NSObject"]),
+                string("key"),
+            ],
+        );
+        let mut bytes = Vec::new();
+        plist::to_writer_binary(&mut bytes, &plist).unwrap();
+
+        let options = ConverterOptions {
+            leave_null_values: true,
+            ..Default::default()
+        };
+        let converter = Converter::from_bytes_with_options(&bytes, options).unwrap();
+        assert!(converter.leave_null_values());
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![dict(vec![
+                ("key", string("key")),
+                ("value", string("$null"))
+            ])])
+        );
+    }
+
+    #[test]
+    fn prefix_compressed_strings_are_expanded() {
+        let prefix_string = |index, suffix| {
+            object(
+                5,
+                vec![
+                    ("NS.prefixtable", uid(3)),
+                    ("NS.prefixindex", int(index)),
+                    ("NS.suffix", string(suffix)),
+                ],
+            )
+        };
+        let options = ConverterOptions {
+            expand_prefix_strings: true,
+            ..Default::default()
+        };
+        let plist = archive(
+            vec![("root", uid(1))],
+            vec![
+                string("$null"),
+                array(2, &[6, 7]),
+                class(&["NSArray", "NSObject"]),
+                array(2, &[4, 8]),
+                string("com.apple."),
+                class(&["NSString", "NSObject"]),
+                prefix_string(0, "finder"),
+                prefix_string(1, "bar"),
+                string("foo"),
+            ],
+        );
+        let converter = Converter::new_with_options(plist, options).unwrap();
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![string("com.apple.finder"), string("foobar")])
+        );
+    }
+
+    #[test]
+    fn top_level_key_may_reference_null() {
+        let plist = archive(
+            vec![("root", uid(0)), ("title", uid(1))],
+            vec![string("$null"), string("Hello")],
+        );
+        let mut converter = Converter::new(plist).unwrap();
+        assert_eq!(
+            converter.decode().unwrap(),
+            dict(vec![("title", string("Hello"))])
+        );
+
+        converter.set_leave_null_values(true);
+        assert_eq!(
+            converter.decode().unwrap(),
+            dict(vec![("root", string("$null")), ("title", string("Hello"))])
+        );
+    }
+
+    #[test]
+    fn null_dictionary_values_are_tolerated() {
+        let mut converter = converter(vec![
+            string("$null"),
+            dictionary(2, &[3, 4], &[0, 5]),
+            class(&["NSDictionary", "NSObject"]),
+            string("missing"),
+            string("name"),
+            string("Alice"),
+        ]);
+        let pair = |key, value| dict(vec![("key", string(key)), ("value", string(value))]);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![pair("name", "Alice")])
+        );
+
+        converter.set_leave_null_values(true);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![pair("missing", "$null"), pair("name", "Alice")])
+        );
+    }
+
+    #[test]
+    fn string_transform_applies_to_every_string() {
+        let mut converter = converter(vec![
+            string("$null"),
+            dictionary(2, &[3, 4], &[5, 6]),
+            class(&["NSDictionary", "NSObject"]),
+            string("name"),
+            string("friends"),
+            string("Alice"),
+            array(7, &[8]),
+            class(&["NSArray", "NSObject"]),
+            string("Bob"),
+        ]);
+        converter.set_string_transform(|s| s.to_uppercase());
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![
+                dict(vec![("key", string("NAME")), ("value", string("ALICE"))]),
+                dict(vec![
+                    ("key", string("FRIENDS")),
+                    ("value", Value::Array(vec![string("BOB")]))
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn class_allowlist_rejects_other_classes() {
+        let mut converter = converter(vec![
+            string("$null"),
+            array(2, &[3]),
+            class(&["NSArray", "NSObject"]),
+            object(4, vec![("name", uid(5))]),
+            class(&["Person", "NSObject"]),
+            string("Alice"),
+        ]);
+        converter.set_class_allowlist(&["NSArray", "NSDictionary"]);
+        assert!(matches!(
+            converter.decode(),
+            Err(ConverterError::DisallowedClass(name)) if name == "Person"
+        ));
+
+        converter.set_class_allowlist(&["NSArray", "Person"]);
+        assert!(converter.decode().is_ok());
+    }
+
+    #[test]
+    fn class_allowlist_applies_to_array_fields_of_custom_classes() {
+        let mut converter = converter(vec![
+            string("$null"),
+            object(2, vec![("members", references(&[3]))]),
+            class(&["Team", "NSObject"]),
+            object(4, vec![("name", uid(5))]),
+            class(&["Person", "NSObject"]),
+            string("Alice"),
+        ]);
+        converter.set_class_allowlist(&["Team"]);
+        assert!(matches!(
+            converter.decode(),
+            Err(ConverterError::DisallowedClass(name)) if name == "Person"
+        ));
+    }
+
+    #[test]
+    fn array_top_is_accepted_with_the_lenient_option() {
+        let plist = dict(vec![
+            ("$version", int(100000)),
+            ("$archiver", string("NSKeyedArchiver")),
+            ("$top", references(&[2, 1])),
+            (
+                "$objects",
+                Value::Array(vec![string("$null"), string("a"), string("b")]),
+            ),
+        ]);
+        assert!(matches!(
+            Converter::new(plist.clone()),
+            Err(ConverterError::WrongValueType("$top", _))
+        ));
+
+        let options = ConverterOptions {
+            lenient_top: true,
+            ..Default::default()
+        };
+        let converter = Converter::new_with_options(plist, options).unwrap();
+        assert_eq!(
+            converter.decode().unwrap(),
+            Value::Array(vec![string("b"), string("a")])
+        );
+        assert_eq!(converter.metadata().top_keys, ["0", "1"]);
+    }
+
+    #[test]
+    fn mangled_xml_decodes_with_the_lenient_option() {
+        // XML plists can't hold uids, so the reference is a string
+        let plist = archive(
+            vec![("root", string("1"))],
+            vec![string("$null"), string("Hello")],
+        );
+        let mut xml = Vec::new();
+        plist::to_writer_xml(&mut xml, &plist).unwrap();
+        let mut mangled = b"\xEF\xBB\xBF".to_vec();
+        for byte in xml {
+            if byte == b'\n' {
+                mangled.push(b'\r');
+            }
+            mangled.push(byte);
+        }
+
+        let options = ConverterOptions {
+            lenient_xml: true,
+            parse_string_uids: true,
+            ..Default::default()
+        };
+        let converter = Converter::from_bytes_with_options(&mangled, options).unwrap();
+        assert_eq!(decode_root(&converter), string("Hello"));
+    }
+
+    #[test]
+    fn sub_roots_decode_independently() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[4]),
+            class(&["NSArray", "NSObject"]),
+            array(2, &[4, 5]),
+            string("shared"),
+            int(7),
+        ]);
+        assert_eq!(
+            converter.decode_sub_roots(&[3, 1]).unwrap(),
+            [
+                Value::Array(vec![string("shared"), int(7)]),
+                Value::Array(vec![string("shared")]),
+            ]
+        );
+        assert!(matches!(
+            converter.decode_sub_roots(&[1, 9]),
+            Err(ConverterError::InvalidObjectReference(9))
+        ));
+    }
+
+    #[test]
+    fn decoded_objects_are_annotated_with_their_source_uids() {
+        let options = ConverterOptions {
+            annotate_source_uids: true,
+            ..Default::default()
+        };
+        let plist = archive(
+            vec![("root", uid(1))],
+            vec![
+                string("$null"),
+                object(2, vec![("friend", uid(4))]),
+                class(&["Person", "NSObject"]),
+                string("unused"),
+                object(2, vec![("name", uid(5))]),
+                string("Bob"),
+            ],
+        );
+        let converter = Converter::new_with_options(plist, options).unwrap();
+        let classes = Value::Array(vec![string("Person"), string("NSObject")]);
+        assert_eq!(
+            decode_root(&converter),
+            dict(vec![
+                ("$classes", classes.clone()),
+                (
+                    "friend",
+                    dict(vec![
+                        ("$classes", classes),
+                        ("name", string("Bob")),
+                        ("$uid", int(4)),
+                    ])
+                ),
+                ("$uid", int(1)),
+            ])
+        );
+    }
+
+    #[test]
+    fn string_references_are_parsed_with_the_option() {
+        let plist = archive(
+            vec![("root", string("1"))],
+            vec![
+                string("$null"),
+                dict(vec![
+                    ("$class", string("2")),
+                    ("NS.objects", Value::Array(vec![string("3")])),
+                ]),
+                class(&["NSArray", "NSObject"]),
+                string("Alice"),
+            ],
+        );
+        assert!(matches!(
+            Converter::new(plist.clone()).unwrap().decode(),
+            Err(ConverterError::ExpectedUIDValue(key)) if key == "root"
+        ));
+
+        let options = ConverterOptions {
+            parse_string_uids: true,
+            ..Default::default()
+        };
+        let converter = Converter::new_with_options(plist, options).unwrap();
+        assert_eq!(decode_root(&converter), Value::Array(vec![string("Alice")]));
+    }
+
+    #[test]
+    fn decode_to_dictionary_returns_the_top_level_keys() {
+        let plist = archive(
+            vec![("title", uid(1)), ("count", uid(2))],
+            vec![string("$null"), string("Hello"), int(3)],
+        );
+        let converter = Converter::new(plist.clone()).unwrap();
+        let decoded = converter.decode_to_dictionary().unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded["title"], string("Hello"));
+        assert_eq!(decoded["count"], int(3));
+
+        let mut plist = plist.into_dictionary().unwrap();
+        plist.insert("$top".to_string(), references(&[1]));
+        let options = ConverterOptions {
+            lenient_top: true,
+            ..Default::default()
+        };
+        let converter = Converter::new_with_options(Value::Dictionary(plist), options).unwrap();
+        assert!(matches!(
+            converter.decode_to_dictionary(),
+            Err(ConverterError::WrongValueType("root", _))
+        ));
+    }
+
+    #[test]
+    fn string_version_is_accepted_only_when_lenient() {
+        let mut plist = archive(vec![("root", uid(1))], vec![string("$null"), int(7)])
+            .into_dictionary()
+            .unwrap();
+        plist.insert("$version".to_string(), string("100000"));
+        let plist = Value::Dictionary(plist);
+        assert!(matches!(
+            Converter::new(plist.clone()),
+            Err(ConverterError::WrongValueType("$version", _))
+        ));
+
+        let options = ConverterOptions {
+            lenient_version: true,
+            ..Default::default()
+        };
+        let converter = Converter::new_with_options(plist, options).unwrap();
+        assert_eq!(decode_root(&converter), int(7));
+    }
+
+    #[test]
+    fn object_transform_rewrites_strings() {
+        let mut converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 5, 6]),
+            class(&["NSArray", "NSObject"]),
+            object(4, vec![("NS.string", string("hello"))]),
+            class(&["NSString", "NSObject"]),
+            string("plain"),
+            int(1),
+        ]);
+        converter.set_object_transform(|class_name, value| match value {
+            Value::String(s) if class_name == "NSString" => Value::String(s.to_uppercase()),
+            value => value,
+        });
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![string("HELLO"), string("PLAIN"), int(1)])
+        );
+    }
+
+    #[test]
+    fn pointer_array_decodes_into_an_array() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 4]),
+            class(&["NSPointerArray", "NSObject"]),
+            string("first"),
+            int(2),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![string("first"), int(2)])
+        );
+    }
+
+    #[test]
+    fn cache_decodes_into_key_value_pairs() {
+        let converter = converter(vec![
+            string("$null"),
+            dictionary(2, &[3], &[4]),
+            class(&["NSCache", "NSObject"]),
+            string("key"),
+            int(5),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![dict(vec![("key", string("key")), ("value", int(5))])])
+        );
+    }
+
+    #[test]
+    fn empty_archive_decodes_into_an_empty_dictionary() {
+        let converter = Converter::new(archive(vec![], vec![])).unwrap();
+        assert_eq!(
+            converter.decode().unwrap(),
+            Value::Dictionary(Dictionary::new())
+        );
+    }
+
+    #[test]
+    fn interleaved_dictionary_pairs_keys_with_values() {
+        let objects = vec![
+            string("$null"),
+            object(2, vec![("NS.objects", references(&[3, 4, 5, 6]))]),
+            class(&["NSDictionary", "NSObject"]),
+            string("name"),
+            string("Alice"),
+            string("age"),
+            int(42),
+        ];
+        let converter = converter(objects.clone());
+        assert!(converter.decode().is_err());
+
+        let options = ConverterOptions {
+            lenient_interleaved_dicts: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(objects, options);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![
+                dict(vec![("key", string("name")), ("value", string("Alice"))]),
+                dict(vec![("key", string("age")), ("value", int(42))]),
+            ])
+        );
+    }
+
+    /// Objects of `depth` arrays, each one holding the next one.
+    fn nested_arrays(depth: u64) -> Vec<Value> {
+        let class_index = depth + 1;
+        let mut objects = vec![string("$null")];
+        objects.extend((1..depth).map(|i| array(class_index, &[i + 1])));
+        objects.push(array(class_index, &[depth + 2]));
+        objects.push(class(&["NSArray", "NSObject"]));
+        objects.push(string("bottom"));
+        objects
+    }
+
+    #[test]
+    fn very_deep_archive_fails_with_the_recommended_depth_limit() {
+        assert_eq!(ConverterOptions::default().max_depth, None);
+
+        let mut deep = converter(nested_arrays(50_000));
+        deep.set_max_depth(Some(RECOMMENDED_MAX_DEPTH));
+        assert!(matches!(
+            deep.decode(),
+            Err(ConverterError::MaxDepthExceeded(RECOMMENDED_MAX_DEPTH))
+        ));
+        assert!(matches!(
+            deep.decode_tree(),
+            Err(ConverterError::MaxDepthExceeded(RECOMMENDED_MAX_DEPTH))
+        ));
+
+        let mut limited = converter(nested_arrays(512));
+        limited.set_max_depth(Some(RECOMMENDED_MAX_DEPTH));
+        let mut value = decode_root(&limited);
+        for _ in 0..RECOMMENDED_MAX_DEPTH {
+            let Value::Array(mut array) = value else {
+                panic!("expected an array");
+            };
+            value = array.remove(0);
+        }
+        assert_eq!(value, string("bottom"));
+    }
+
+    #[test]
+    fn very_deep_archive_decodes_without_a_depth_limit() {
+        let value = decode_root(&converter(nested_arrays(50_000)));
+        let mut level = &value;
+        for _ in 0..50_000 {
+            level = &level.as_array().expect("expected an array")[0];
+        }
+        assert_eq!(level, &string("bottom"));
+        drop_iteratively(value);
+    }
+
+    #[test]
+    fn inline_class_name_decodes_as_a_custom_class() {
+        let converter = converter(vec![
+            string("$null"),
+            dict(vec![("$classname", string("Person")), ("name", uid(2))]),
+            string("Alice"),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            dict(vec![
+                ("$classes", Value::Array(vec![string("Person")])),
+                ("name", string("Alice")),
+            ])
+        );
+    }
+
+    #[test]
+    fn dual_dictionary_holds_consistent_map_and_pairs() {
+        let options = ConverterOptions {
+            dual_dict_representation: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                dictionary(2, &[3, 4], &[5, 6]),
+                class(&["NSDictionary", "NSObject"]),
+                string("name"),
+                int(7),
+                string("Alice"),
+                string("seven"),
+            ],
+            options,
+        );
+        let decoded = decode_root(&converter).into_dictionary().unwrap();
+        assert_eq!(
+            decoded["$pairs"],
+            Value::Array(vec![
+                dict(vec![("key", string("name")), ("value", string("Alice"))]),
+                dict(vec![("key", int(7)), ("value", string("seven"))]),
+            ])
+        );
+        assert_eq!(decoded["$map"], dict(vec![("name", string("Alice"))]));
+    }
+
+    #[test]
+    fn core_foundation_collections_decode_like_public_ones() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3]),
+            class(&["__NSCFArray", "NSMutableArray", "NSArray", "NSObject"]),
+            dictionary(4, &[5], &[6]),
+            class(&["__NSCFDictionary", "NSMutableDictionary", "NSDictionary"]),
+            string("key"),
+            int(1),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![Value::Array(vec![dict(vec![
+                ("key", string("key")),
+                ("value", int(1)),
+            ])])])
+        );
+    }
+
+    #[test]
+    fn null_and_class_setters_are_independent() {
+        let mut converter = converter(vec![
+            string("$null"),
+            array(2, &[0, 3]),
+            class(&["NSArray", "NSObject"]),
+            int(1),
+        ]);
+        converter.set_leave_null_values(true);
+        assert!(converter.leave_null_values());
+        assert!(!converter.treat_all_as_classes());
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![string("$null"), int(1)])
+        );
+
+        converter.set_leave_null_values(false);
+        converter.set_treat_all_as_classes(true);
+        assert!(converter.treat_all_as_classes());
+        assert!(!converter.leave_null_values());
+        let decoded = decode_root(&converter).into_dictionary().unwrap();
+        assert_eq!(decoded["NS.objects"], Value::Array(vec![int(1)]));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn memory_mapped_file_decodes() {
+        let plist = archive(vec![("root", uid(1))], vec![string("$null"), int(42)]);
+        let path = std::env::temp_dir().join(format!(
+            "nskeyedarchiver_converter_mmap_{}.plist",
+            std::process::id()
+        ));
+        plist.to_file_binary(&path).unwrap();
+        let converter = Converter::from_mmap(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            converter.unwrap().decode().unwrap(),
+            dict(vec![("root", int(42))])
+        );
+    }
+
+    #[test]
+    fn shared_object_is_decoded_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let keys: Vec<u64> = (4..14).collect();
+        let mut objects = vec![
+            string("$null"),
+            dictionary(2, &keys, &[3; 10]),
+            class(&["NSDictionary", "NSObject"]),
+            object(14, vec![("name", uid(15))]),
+        ];
+        objects.extend(keys.iter().map(|key| string(&format!("key{key}"))));
+        objects.push(class(&["Person", "NSObject"]));
+        objects.push(string("Alice"));
+        let mut converter = converter(objects);
+
+        let visits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&visits);
+        converter.set_object_transform(move |class_name, value| {
+            if class_name == "Person" {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            value
+        });
+        let decoded = decode_root(&converter);
+        assert_eq!(decoded.as_array().unwrap().len(), 10);
+        assert_eq!(visits.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn very_deep_archive_fails_cleanly_in_every_decoding_mode() {
+        let mut converter = converter(nested_dictionaries(50_000));
+        converter.set_max_depth(Some(RECOMMENDED_MAX_DEPTH));
+        assert!(matches!(
+            converter.decode_preserving_uids(),
+            Err(ConverterError::MaxDepthExceeded(RECOMMENDED_MAX_DEPTH))
+        ));
+        assert!(matches!(
+            converter.decode_key("root"),
+            Err(ConverterError::MaxDepthExceeded(RECOMMENDED_MAX_DEPTH))
+        ));
+        let (_, errors) = converter.decode_lossy();
+        assert!(matches!(
+            errors.as_slice(),
+            [ConverterError::MaxDepthExceeded(RECOMMENDED_MAX_DEPTH)]
+        ));
+    }
+
+    #[test]
+    fn jagged_array_is_decoded_at_every_level() {
+        let converter = converter(vec![
+            string("$null"),
+            object(
+                2,
+                vec![(
+                    "NS.objects",
+                    Value::Array(vec![
+                        references(&[3]),
+                        Value::Array(vec![uid(4), references(&[3, 4])]),
+                        uid(3),
+                    ]),
+                )],
+            ),
+            class(&["NSArray", "NSObject"]),
+            string("a"),
+            int(2),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![
+                Value::Array(vec![string("a")]),
+                Value::Array(vec![int(2), Value::Array(vec![string("a"), int(2)])]),
+                string("a"),
+            ])
+        );
+    }
+
+    #[test]
+    fn reference_counts_are_annotated() {
+        let options = ConverterOptions {
+            annotate_reference_counts: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                object(2, vec![("friend", uid(3)), ("partner", uid(3))]),
+                class(&["Person", "NSObject"]),
+                object(2, vec![]),
+            ],
+            options,
+        );
+        let root = decode_root(&converter).into_dictionary().unwrap();
+        assert_eq!(root["$refcount"], int(1));
+        let friend = root["friend"].as_dictionary().unwrap();
+        assert_eq!(friend["$refcount"], int(2));
+    }
+
+    #[test]
+    fn cycle_is_rejected_but_diamond_is_not() {
+        let options = ConverterOptions {
+            reject_circular_references: true,
+            ..Default::default()
+        };
+        let cycle = converter_with_options(
+            vec![
+                string("$null"),
+                object(2, vec![("child", uid(3))]),
+                class(&["Node", "NSObject"]),
+                object(2, vec![("parent", uid(1))]),
+            ],
+            options.clone(),
+        );
+        assert!(matches!(
+            cycle.decode(),
+            Err(ConverterError::CyclicReference(1))
+        ));
+
+        let diamond = converter_with_options(
+            vec![
+                string("$null"),
+                object(2, vec![("left", uid(3)), ("right", uid(4))]),
+                class(&["Node", "NSObject"]),
+                object(2, vec![("bottom", uid(5))]),
+                object(2, vec![("bottom", uid(5))]),
+                object(2, vec![("name", uid(6))]),
+                string("shared"),
+            ],
+            options,
+        );
+        let root = decode_root(&diamond).into_dictionary().unwrap();
+        let bottom = |side: &str| root[side].as_dictionary().unwrap()["bottom"].clone();
+        assert_eq!(bottom("left"), bottom("right"));
+        assert_eq!(
+            bottom("left").as_dictionary().unwrap()["name"],
+            string("shared")
+        );
+    }
+
+    #[test]
+    fn set_decodes_into_a_flat_array() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 4, 5]),
+            class(&["NSSet", "NSObject"]),
+            string("apple"),
+            string("banana"),
+            string("cherry"),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![string("apple"), string("banana"), string("cherry")])
+        );
+    }
+
+    #[test]
+    fn shared_object_is_inlined_a_limited_number_of_times() {
+        let options = ConverterOptions {
+            max_expansions_per_object: Some(2),
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                array(2, &[3; 50]),
+                class(&["NSArray", "NSObject"]),
+                object(4, vec![("name", uid(5))]),
+                class(&["Person", "NSObject"]),
+                string("Alice"),
+            ],
+            options,
+        );
+        let decoded = decode_root(&converter);
+        let elements = decoded.as_array().unwrap();
+        assert_eq!(elements.len(), 50);
+        let marker = dict(vec![("$ref", int(3))]);
+        assert!(elements[..2].iter().all(|element| *element != marker));
+        assert!(elements[2..].iter().all(|element| *element == marker));
+    }
+
+    #[test]
+    fn embedded_archives_are_detected_by_their_magic() {
+        let nested = archive(
+            vec![("root", uid(1))],
+            vec![
+                string("$null"),
+                object(2, vec![("name", uid(3))]),
+                class(&["Person", "NSObject"]),
+                string("Alice"),
+            ],
+        );
+        let mut bytes = Vec::new();
+        plist::to_writer_binary(&mut bytes, &nested).unwrap();
+        let random = b"bplist\xff\x00\x13\x37 not really a plist".to_vec();
+        let options = ConverterOptions {
+            decode_embedded_archives: true,
+            ..Default::default()
+        };
+        let mut converter = converter_with_options(
+            vec![
+                string("$null"),
+                array(2, &[3, 4]),
+                class(&["NSArray", "NSObject"]),
+                Value::Data(bytes),
+                Value::Data(random.clone()),
+            ],
+            options,
+        );
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![
+                dict(vec![(
+                    "root",
+                    dict(vec![
+                        (
+                            "$classes",
+                            Value::Array(vec![string("Person"), string("NSObject")])
+                        ),
+                        ("name", string("Alice")),
+                    ])
+                )]),
+                Value::Data(random),
+            ])
+        );
+
+        converter.set_class_allowlist(&["NSArray"]);
+        assert!(matches!(
+            converter.decode(),
+            Err(ConverterError::DisallowedClass(name)) if name == "Person"
+        ));
+    }
+
+    #[test]
+    fn ordered_set_keeps_the_element_order() {
+        let converter = converter(vec![
+            string("$null"),
+            object(
+                2,
+                vec![
+                    ("NS.objects", references(&[3, 4, 5, 6, 7])),
+                    ("NS.orderedSet", Value::Boolean(true)),
+                ],
+            ),
+            class(&["NSOrderedSet", "NSObject"]),
+            int(5),
+            int(3),
+            int(9),
+            int(1),
+            int(7),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![int(5), int(3), int(9), int(1), int(7)])
+        );
+    }
+
+    #[test]
+    fn only_mutable_data_is_marked_as_mutable() {
+        let options = ConverterOptions {
+            preserve_mutability: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                array(2, &[3, 5]),
+                class(&["NSArray", "NSObject"]),
+                object(4, vec![("NS.data", Value::Data(vec![1, 2]))]),
+                class(&["NSData", "NSObject"]),
+                object(6, vec![("NS.data", Value::Data(vec![3, 4]))]),
+                class(&["NSMutableData", "NSData", "NSObject"]),
+            ],
+            options,
+        );
+        let decoded = decode_root(&converter).into_array().unwrap();
+        assert_eq!(decoded[0], Value::Data(vec![1, 2]));
+        let mutable = decoded[1].as_dictionary().unwrap();
+        assert_eq!(mutable["$mutable"], Value::Boolean(true));
+        assert_eq!(mutable["NS.data"], Value::Data(vec![3, 4]));
+    }
+
+    #[test]
+    fn custom_reserved_keys_are_used_for_every_lookup() {
+        let custom_class = |names: &[&str]| {
+            dict(vec![
+                ("$classname", string(names[0])),
+                (
+                    "@chain",
+                    Value::Array(names.iter().map(|name| string(name)).collect()),
+                ),
+            ])
+        };
+        let mut converter = converter(vec![
+            string("$null"),
+            dict(vec![("@cls", uid(2)), ("items", references(&[3]))]),
+            custom_class(&["NSArray", "NSObject"]),
+            dict(vec![
+                ("@cls", uid(4)),
+                ("names", references(&[5])),
+                ("items", references(&[6])),
+            ]),
+            custom_class(&["NSDictionary", "NSObject"]),
+            string("name"),
+            string("Alice"),
+        ]);
+        converter.set_reserved_keys(ReservedKeys {
+            class: "@cls".to_string(),
+            classes: "@chain".to_string(),
+            objects: "items".to_string(),
+            keys: "names".to_string(),
+        });
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![Value::Array(vec![dict(vec![
+                ("key", string("name")),
+                ("value", string("Alice")),
+            ])])])
+        );
+    }
+
+    #[test]
+    fn data_object_decodes_into_its_bytes() {
+        let bytes: Vec<u8> = (0..16).map(|i| i * 17).collect();
+        let mut converter = converter(vec![
+            string("$null"),
+            object(2, vec![("NS.data", Value::Data(bytes.clone()))]),
+            class(&["NSMutableData", "NSData", "NSObject"]),
+        ]);
+        assert_eq!(decode_root(&converter), Value::Data(bytes.clone()));
+
+        converter.set_treat_all_as_classes(true);
+        let decoded = decode_root(&converter).into_dictionary().unwrap();
+        assert_eq!(decoded["NS.data"], Value::Data(bytes));
+    }
+
+    #[test]
+    fn wrapped_numbers_decode_into_bare_scalars() {
+        for number in [int(-3), Value::Real(1.5), Value::Boolean(true)] {
+            let converter = converter(vec![
+                string("$null"),
+                object(2, vec![("NS.number", number.clone())]),
+                class(&["NSNumber", "NSValue", "NSObject"]),
+            ]);
+            assert_eq!(decode_root(&converter), number);
+        }
+    }
+
+    #[test]
+    fn only_contiguous_integer_keys_are_coalesced() {
+        let options = ConverterOptions {
+            coalesce_integer_indexed_dicts: true,
+            ..Default::default()
+        };
+        let indexed = |second_key: i64| {
+            converter_with_options(
+                vec![
+                    string("$null"),
+                    dictionary(2, &[3, 4], &[5, 6]),
+                    class(&["NSDictionary", "NSObject"]),
+                    int(second_key),
+                    int(0),
+                    string("second"),
+                    string("first"),
+                ],
+                options.clone(),
+            )
+        };
+        assert_eq!(
+            decode_root(&indexed(1)),
+            Value::Array(vec![string("first"), string("second")])
+        );
+        assert_eq!(
+            decode_root(&indexed(2)),
+            Value::Array(vec![
+                dict(vec![("key", int(2)), ("value", string("second"))]),
+                dict(vec![("key", int(0)), ("value", string("first"))]),
+            ])
+        );
+    }
+
+    #[test]
+    fn lost_objects_are_dropped_with_lenient_null_gaps() {
+        let objects = vec![
+            string("$null"),
+            array(2, &[3, 4, 9]),
+            class(&["NSArray", "NSObject"]),
+            string("kept"),
+            object(5, vec![("name", uid(3))]),
+            string("$null"),
+        ];
+        let converter = converter(objects.clone());
+        assert!(converter.decode().is_err());
+
+        let options = ConverterOptions {
+            lenient_null_gaps: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(objects, options);
+        assert_eq!(decode_root(&converter), Value::Array(vec![string("kept")]));
+    }
+
+    #[test]
+    fn super_object_fields_are_merged_or_nested() {
+        let objects = vec![
+            string("$null"),
+            object(2, vec![("name", uid(4)), ("$super", uid(3))]),
+            class(&["Employee", "Person", "NSObject"]),
+            object(6, vec![("name", uid(5)), ("age", int(30))]),
+            string("Alice"),
+            string("Person name"),
+            class(&["Person", "NSObject"]),
+        ];
+        let employee = |decoded: Value| decoded.into_dictionary().unwrap();
+        let person_classes = Value::Array(vec![string("Person"), string("NSObject")]);
+
+        let merged = employee(decode_root(&converter(objects.clone())));
+        assert_eq!(merged["name"], string("Alice"));
+        assert_eq!(merged["age"], int(30));
+        assert!(!merged.contains_key("super"));
+
+        let options = ConverterOptions {
+            nest_super_objects: true,
+            ..Default::default()
+        };
+        let nested = employee(decode_root(&converter_with_options(objects, options)));
+        assert_eq!(nested["name"], string("Alice"));
+        assert!(!nested.contains_key("age"));
+        assert_eq!(
+            nested["super"],
+            dict(vec![
+                ("$classes", person_classes),
+                ("name", string("Person name")),
+                ("age", int(30)),
+            ])
+        );
+    }
+
+    #[test]
+    fn dropped_nulls_are_reported_on_every_decode() {
+        use std::sync::{Arc, Mutex};
+
+        let mut converter = converter(vec![
+            string("$null"),
+            array(2, &[0, 3, 0]),
+            class(&["NSArray", "NSObject"]),
+            string("kept"),
+        ]);
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&warnings);
+        converter.set_warning_sink(move |message| sink.lock().unwrap().push(message.to_string()));
+
+        for _ in 0..2 {
+            assert_eq!(decode_root(&converter), Value::Array(vec![string("kept")]));
+            let reported = std::mem::take(&mut *warnings.lock().unwrap());
+            assert_eq!(reported, vec!["Dropped a null element of array #1"; 2]);
+        }
+    }
+
+    #[test]
+    fn auto_dict_style_builds_maps_only_for_unique_string_keys() {
+        let decode_with_keys = |first_key: Value, second_key: Value| {
+            let mut converter = converter(vec![
+                string("$null"),
+                dictionary(2, &[3, 4], &[5, 6]),
+                class(&["NSDictionary", "NSObject"]),
+                first_key,
+                second_key,
+                string("one"),
+                string("two"),
+            ]);
+            converter.set_dict_style(DictStyle::Auto);
+            decode_root(&converter)
+        };
+        let pairs = |first_key: Value, second_key: Value| {
+            Value::Array(vec![
+                dict(vec![("key", first_key), ("value", string("one"))]),
+                dict(vec![("key", second_key), ("value", string("two"))]),
+            ])
+        };
+
+        assert_eq!(
+            decode_with_keys(string("a"), string("b")),
+            dict(vec![("a", string("one")), ("b", string("two"))])
+        );
+        assert_eq!(
+            decode_with_keys(string("a"), int(2)),
+            pairs(string("a"), int(2))
+        );
+        assert_eq!(
+            decode_with_keys(string("a"), string("a")),
+            pairs(string("a"), string("a"))
+        );
+    }
+
+    #[test]
+    fn decode_key_only_visits_the_objects_of_that_key() {
+        let plist = archive(
+            vec![("root", uid(1)), ("bulky", uid(3))],
+            vec![
+                string("$null"),
+                array(2, &[4]),
+                class(&["NSArray", "NSObject"]),
+                array(2, &[99]),
+                string("wanted"),
+            ],
+        );
+        let converter = Converter::new(plist).unwrap();
+        assert!(matches!(
+            converter.decode(),
+            Err(ConverterError::InvalidObjectReference(99))
+        ));
+        assert_eq!(
+            converter.decode_key("root").unwrap(),
+            Some(Value::Array(vec![string("wanted")]))
+        );
+        assert_eq!(converter.decode_key("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn references_beyond_32_bits_fail_cleanly() {
+        for reference in [(1 << 32) + 1, u64::MAX] {
+            let converter = converter(vec![
+                string("$null"),
+                array(2, &[reference]),
+                class(&["NSArray", "NSObject"]),
+            ]);
+            assert!(matches!(
+                converter.decode(),
+                Err(ConverterError::InvalidObjectReference(r)) if r == reference
+            ));
+        }
+        // The class would be found at index 2 if the reference was truncated
+        let converter = converter(vec![
+            string("$null"),
+            object((1 << 32) + 2, vec![]),
+            class(&["Person", "NSObject"]),
+        ]);
+        assert!(converter.decode().is_err());
+    }
+
+    #[test]
+    fn singleton_arrays_are_unwrapped_but_sets_are_not() {
+        let options = ConverterOptions {
+            unwrap_singleton_arrays: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                array(2, &[3, 4, 5]),
+                class(&["NSArray", "NSObject"]),
+                array(2, &[6]),
+                array(2, &[6, 7]),
+                array(8, &[6]),
+                string("a"),
+                string("b"),
+                class(&["NSSet", "NSObject"]),
+            ],
+            options,
+        );
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![
+                string("a"),
+                Value::Array(vec![string("a"), string("b")]),
+                Value::Array(vec![string("a")]),
+            ])
+        );
+    }
+
+    #[test]
+    fn metadata_describes_the_archive_header() {
+        let plist = archive(
+            vec![("root", uid(1)), ("extra", uid(2))],
+            // The second object is broken, but objects aren't decoded
+            vec![string("$null"), int(1), object(99, vec![])],
+        );
+        let converter = Converter::new(plist).unwrap();
+        assert_eq!(
+            converter.metadata(),
+            ArchiveMetadata {
+                archiver: "NSKeyedArchiver".to_string(),
+                version: 100000,
+                top_keys: vec!["root".to_string(), "extra".to_string()],
+                object_count: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn object_key_pairs_are_sorted_stably() {
+        let options = ConverterOptions {
+            sort_object_key_pairs: true,
+            ..Default::default()
+        };
+        let converter_with_keys = |keys: &[u64], values: &[u64]| {
+            converter_with_options(
+                vec![
+                    string("$null"),
+                    dictionary(2, keys, values),
+                    class(&["NSDictionary", "NSObject"]),
+                    object(6, vec![("id", int(1))]),
+                    object(6, vec![("id", int(2))]),
+                    object(6, vec![("id", int(3))]),
+                    class(&["Key", "NSObject"]),
+                    string("one"),
+                    string("two"),
+                    string("three"),
+                ],
+                options.clone(),
+            )
+        };
+        let forward = converter_with_keys(&[3, 4, 5], &[7, 8, 9]);
+        let backward = converter_with_keys(&[5, 4, 3], &[9, 8, 7]);
+        let decoded = decode_root(&forward);
+        assert_eq!(decoded.as_array().unwrap().len(), 3);
+        assert_eq!(decoded, decode_root(&forward));
+        assert_eq!(decoded, decode_root(&backward));
+    }
+
+    #[test]
+    fn max_depth_limits_nesting() {
+        let mut deep = converter(nested_arrays(5));
+        deep.set_max_depth(Some(3));
+        assert!(matches!(
+            deep.decode(),
+            Err(ConverterError::MaxDepthExceeded(3))
+        ));
+        deep.set_max_depth(None);
+        assert!(deep.decode().is_ok());
+
+        let mut shallow = converter(nested_arrays(2));
+        shallow.set_max_depth(Some(3));
+        assert_eq!(
+            decode_root(&shallow),
+            Value::Array(vec![Value::Array(vec![string("bottom")])])
+        );
+    }
+
+    #[test]
+    fn length_prefixed_archive_is_validated() {
+        let plist = archive(vec![("root", uid(1))], vec![string("$null"), int(5)]);
+        let mut payload = Vec::new();
+        plist::to_writer_binary(&mut payload, &plist).unwrap();
+        let framed = |length: u32| {
+            let mut bytes = length.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&payload);
+            bytes
+        };
+
+        let converter = Converter::from_length_prefixed_bytes(&framed(payload.len() as u32));
+        assert_eq!(decode_root(&converter.unwrap()), int(5));
+
+        let wrong_length = payload.len() as u32 + 1;
+        assert!(matches!(
+            Converter::from_length_prefixed_bytes(&framed(wrong_length)),
+            Err(ConverterError::LengthPrefixMismatch(length, actual))
+                if length == wrong_length && actual == payload.len()
+        ));
+        assert!(matches!(
+            Converter::from_length_prefixed_bytes(&[0, 0]),
+            Err(ConverterError::MissingLengthPrefix)
+        ));
+    }
+
+    #[test]
+    fn all_scalars_are_stringified() {
+        let options = ConverterOptions {
+            stringify_all_scalars: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                array(2, &[3, 4, 5, 6, 7, 8]),
+                class(&["NSArray", "NSObject"]),
+                int(-7),
+                Value::Real(2.5),
+                Value::Boolean(false),
+                Value::Data(b"hi".to_vec()),
+                Value::Date(std::time::SystemTime::UNIX_EPOCH.into()),
+                object(9, vec![("age", int(30))]),
+                class(&["Person", "NSObject"]),
+            ],
+            options,
+        );
+        assert_eq!(
+            decode_root(&converter),
+            Value::Array(vec![
+                string("-7"),
+                string("2.5"),
+                string("false"),
+                string("aGk="),
+                string("1970-01-01T00:00:00Z"),
+                dict(vec![
+                    (
+                        "$classes",
+                        Value::Array(vec![string("Person"), string("NSObject")])
+                    ),
+                    ("age", string("30")),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_broken_node() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 3, 3, 4]),
+            class(&["NSArray", "NSObject"]),
+            string("fine"),
+            object(99, vec![]),
+        ]);
+        let Err(ConverterError::InvalidObjectEncoding { object_ref, path }) = converter.decode()
+        else {
+            panic!("expected an invalid encoding error");
+        };
+        assert_eq!(object_ref, 99);
+        assert_eq!(path, "root.NS.objects[3].$class");
+    }
+
+    #[test]
+    fn lossy_decoding_keeps_the_good_parts() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 42, 4]),
+            class(&["NSArray", "NSObject"]),
+            string("first"),
+            string("last"),
+        ]);
+        assert!(converter.decode().is_err());
+
+        let (decoded, errors) = converter.decode_lossy();
+        assert_eq!(
+            decoded,
+            dict(vec![(
+                "root",
+                Value::Array(vec![
+                    string("first"),
+                    string("<decode error>"),
+                    string("last")
+                ])
+            )])
+        );
+        assert!(matches!(
+            errors.as_slice(),
+            [ConverterError::InvalidObjectReference(42)]
+        ));
+    }
+
+    #[test]
+    fn oversized_collection_is_rejected() {
+        let options = ConverterOptions {
+            limits: DecodeLimits {
+                max_collection_len: Some(3),
+            },
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                array(2, &[4, 5]),
+                class(&["NSArray", "NSObject"]),
+                array(2, &[6; 4]),
+                array(2, &[3, 6, 6]),
+                array(2, &[6, 6, 6]),
+                string("element"),
+            ],
+            options,
+        );
+        assert!(matches!(
+            converter.decode(),
+            Err(ConverterError::CollectionTooLarge { index: 3, len: 4 })
+        ));
+    }
+
+    #[test]
+    fn raw_objects_can_be_inspected_by_reference() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3]),
+            class(&["NSMutableArray", "NSArray", "NSObject"]),
+            string("element"),
+        ]);
+        assert_eq!(converter.raw_object(1), Some(&array(2, &[3])));
+        assert_eq!(
+            converter.class_names_for(1).unwrap(),
+            vec!["NSMutableArray", "NSArray", "NSObject"]
+        );
+
+        assert_eq!(converter.raw_object(0), None);
+        assert_eq!(converter.raw_object(4), None);
+        assert!(matches!(
+            converter.class_names_for(4),
+            Err(ConverterError::InvalidObjectReference(4))
+        ));
+    }
+
+    #[test]
+    fn converter_decodes_from_several_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Converter>();
+
+        let mut converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 3]),
+            class(&["NSArray", "NSObject"]),
+            string("shared"),
+        ]);
+        converter.set_string_transform(|s| s.to_uppercase());
+        let converter = std::sync::Arc::new(converter);
+        let expected = Value::Array(vec![string("SHARED"), string("SHARED")]);
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let converter = std::sync::Arc::clone(&converter);
+                std::thread::spawn(move || decode_root(&converter))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+}
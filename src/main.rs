@@ -1,5 +1,9 @@
-use clap::{Args, Parser};
-use nskeyedarchiver_converter::{Converter, ConverterError};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser};
+use nskeyedarchiver_converter::{
+    plist::{Dictionary, Value},
+    Converter, ConverterError,
+};
+use std::ops::Range;
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -21,6 +25,27 @@ struct Arguments {
     /// By default those are transformed into native plist structures.
     #[arg(short)]
     treat_all_as_classes: bool,
+
+    /// Export raw $objects entries in a range (e.g. 10..20) instead of decoding.
+    /// END is exclusive and must not run past the end of $objects. Index 0 is
+    /// reserved for $null, so START must be at least 1
+    #[arg(long, value_name = "START..END", value_parser = parse_range)]
+    objects_range: Option<Range<u64>>,
+}
+
+fn parse_range(s: &str) -> Result<Range<u64>, String> {
+    let Some((start, end)) = s.split_once("..") else {
+        return Err("expected a range in a form of START..END".to_string());
+    };
+    let start: u64 = start.parse().map_err(|e| format!("invalid start: {e}"))?;
+    let end: u64 = end.parse().map_err(|e| format!("invalid end: {e}"))?;
+    if start > end {
+        return Err("start of the range is greater than its end".to_string());
+    }
+    if start == 0 {
+        return Err("start of the range must be at least 1, index 0 is $null".to_string());
+    }
+    Ok(start..end)
 }
 
 #[cfg(feature = "exe_serde_json")]
@@ -57,29 +82,71 @@ fn main() -> Result<(), ConverterError> {
     let args = Arguments::parse();
     let mut decoded_file = Converter::from_file(args.plist_in)?;
 
+    if let Some(range) = args.objects_range {
+        let Some(objects) = range
+            .clone()
+            .map(|uid| decoded_file.raw_object(uid).map(uids_to_dictionaries))
+            .collect()
+        else {
+            Arguments::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!("range {range:?} of --objects-range runs past the end of $objects"),
+                )
+                .exit();
+        };
+        return write_output(&Value::Array(objects), args.output_format, &args.file_out);
+    }
+
     decoded_file.set_leave_null_values(args.leave_null);
     decoded_file.set_treat_all_as_classes(args.treat_all_as_classes);
     let decoded_value = decoded_file.decode()?;
 
-    if let Some(output_format) = args.output_format {
-        if output_format.plist_binary {
-            decoded_value.to_file_binary(&args.file_out)?
+    write_output(&decoded_value, args.output_format, &args.file_out)
+}
+
+/// Replaces Uids with `{"CF$UID": <uid>}` dictionaries, since XML plists
+/// can't store them.
+fn uids_to_dictionaries(value: &Value) -> Value {
+    // Grow the stack on the heap, since archives may be nested deeply
+    stacker::maybe_grow(64 * 1024, 1024 * 1024, || {
+        uids_to_dictionaries_unguarded(value)
+    })
+}
+
+fn uids_to_dictionaries_unguarded(value: &Value) -> Value {
+    match value {
+        Value::Uid(uid) => {
+            let mut dict = Dictionary::new();
+            dict.insert("CF$UID".to_string(), Value::Integer(uid.get().into()));
+            Value::Dictionary(dict)
+        }
+        Value::Array(array) => Value::Array(array.iter().map(uids_to_dictionaries).collect()),
+        Value::Dictionary(dict) => {
+            let mut converted = Dictionary::new();
+            for (key, element) in dict {
+                converted.insert(key.clone(), uids_to_dictionaries(element));
+            }
+            Value::Dictionary(converted)
         }
+        _ => value.clone(),
+    }
+}
 
+fn write_output(
+    value: &Value,
+    output_format: Option<OutputFormat>,
+    file_out: &str,
+) -> Result<(), ConverterError> {
+    match output_format {
+        Some(output_format) if output_format.plist_binary => value.to_file_binary(file_out)?,
         #[cfg(feature = "exe_serde_json")]
-        if output_format.json {
-            use std::{
-                fs::File,
-                io::{BufWriter, Write},
-            };
-            let json = serde_json::to_string(&decoded_value).unwrap();
-            let mut output = File::create(&args.file_out).unwrap();
-            let mut writer = BufWriter::new(&mut output);
-            writer.write_all(json.as_bytes()).unwrap();
-            return Ok(());
+        Some(output_format) if output_format.json => {
+            let json = nskeyedarchiver_converter::to_json_string(value)?;
+            std::fs::write(file_out, json)?;
         }
-    } else {
-        decoded_value.to_file_xml(&args.file_out)?
+        // `-p` or no format flag
+        _ => value.to_file_xml(file_out)?,
     }
 
     Ok(())
@@ -0,0 +1,429 @@
+//! Decoding of `NSValue` objects that wrap geometry structures.
+
+use crate::foundation::NS_DATA_KEY;
+use crate::{Converter, ConverterError, DecodeState, NULL_OBJECT_REFERENCE_NAME};
+use plist::{Dictionary, Value};
+
+/// Key that holds a tag of a wrapped struct kind.
+const SPECIAL_KEY: &str = "NS.special";
+/// Keys that may hold the Objective-C type encoding of a wrapped struct.
+const OBJC_TYPE_KEYS: [&str; 2] = ["NS.objctype", "objCType"];
+/// Key that holds edge insets as a `{top, left, bottom, right}` string.
+const EDGE_INSETS_KEY: &str = "NS.edgeinsetsval";
+/// Key that holds a point as a `{x, y}` string or as binary data.
+const POINT_KEY: &str = "NS.pointval";
+/// Key that holds a size as a `{width, height}` string or as binary data.
+const SIZE_KEY: &str = "NS.sizeval";
+/// Key that holds a rect as a `{{x, y}, {width, height}}` string or as binary
+/// data.
+const RECT_KEY: &str = "NS.rectval";
+const RANGE_LOCATION_KEY: &str = "NS.rangeval.location";
+const RANGE_LENGTH_KEY: &str = "NS.rangeval.length";
+
+const POINT_FIELDS: [&str; 2] = ["x", "y"];
+const SIZE_FIELDS: [&str; 2] = ["width", "height"];
+
+/// `NSNotFound`, which is used as a location of an empty range.
+const NS_NOT_FOUND: i64 = i64::MAX;
+
+/// A kind of struct wrapped by a `NSValue`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ValueKind {
+    Point,
+    Size,
+    Rect,
+    Range,
+    EdgeInsets,
+}
+
+impl ValueKind {
+    /// Determines a struct kind from a `NS.special` tag.
+    fn from_special(tag: u64) -> Option<Self> {
+        match tag {
+            1 => Some(Self::Point),
+            2 => Some(Self::Size),
+            3 => Some(Self::Rect),
+            4 => Some(Self::Range),
+            _ => None,
+        }
+    }
+
+    /// Determines a struct kind from an Objective-C type encoding, e.g.
+    /// `{UIEdgeInsets=dddd}`.
+    fn from_objc_type(objc_type: &str) -> Option<Self> {
+        let name = objc_type.strip_prefix('{')?.split('=').next()?;
+        match name.trim_start_matches('_') {
+            // Legacy archives use the Foundation names of geometry types
+            "CGPoint" | "NSPoint" => Some(Self::Point),
+            "CGSize" | "NSSize" => Some(Self::Size),
+            "CGRect" | "NSRect" => Some(Self::Rect),
+            "NSRange" => Some(Self::Range),
+            // AppKit and UIKit use different names for the same layout
+            "NSEdgeInsets" | "UIEdgeInsets" => Some(Self::EdgeInsets),
+            _ => None,
+        }
+    }
+}
+
+impl Converter {
+    /// Decodes a `NSValue` into a dictionary of its struct fields.
+    ///
+    /// Returns `None` if the wrapped value isn't recognized, so the caller can
+    /// fall back to decoding it as a regular class.
+    pub(crate) fn decode_ns_value(
+        &self,
+        dict: &Dictionary,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        let Some(kind) = self.ns_value_kind(dict, state)? else {
+            return self.decode_boxed_number(dict, state);
+        };
+
+        let (key, names): (_, &[&str]) = match kind {
+            ValueKind::Range => return self.decode_range(dict, state),
+            ValueKind::Point => (POINT_KEY, &POINT_FIELDS),
+            ValueKind::Size => (SIZE_KEY, &SIZE_FIELDS),
+            ValueKind::Rect => {
+                let count = POINT_FIELDS.len() + SIZE_FIELDS.len();
+                return Ok(self.raw_numbers_field(dict, RECT_KEY, count).map(rect_dict));
+            }
+            ValueKind::EdgeInsets => (EDGE_INSETS_KEY, &["top", "left", "bottom", "right"]),
+        };
+        let Some(numbers) = self.raw_numbers_field(dict, key, names.len()) else {
+            return Ok(None);
+        };
+        Ok(Some(Value::Dictionary(numbers_dict(names, &numbers))))
+    }
+
+    /// Decodes a `NSValue` that only holds a reference to a number into that
+    /// number.
+    fn decode_boxed_number(
+        &self,
+        dict: &Dictionary,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        let mut payload = dict.iter().filter(|(key, _)| {
+            **key != self.reserved_keys.class
+                && *key != SPECIAL_KEY
+                && !OBJC_TYPE_KEYS.contains(&key.as_str())
+        });
+        let (Some((key, Value::Uid(uid))), None) = (payload.next(), payload.next()) else {
+            return Ok(None);
+        };
+
+        match self.within(
+            || key.clone(),
+            state,
+            |state| self.decode_object(uid, state),
+        )? {
+            Some(number @ (Value::Integer(_) | Value::Real(_))) => Ok(Some(number)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Decodes a `NSRange` into a `{location, length}` dictionary. A
+    /// `NSNotFound` location is treated as a null value.
+    fn decode_range(
+        &self,
+        dict: &Dictionary,
+        state: &mut DecodeState,
+    ) -> Result<Option<Value>, ConverterError> {
+        let (Some(location @ Value::Integer(_)), Some(length @ Value::Integer(_))) = (
+            self.decode_field(dict, RANGE_LOCATION_KEY, state)?,
+            self.decode_field(dict, RANGE_LENGTH_KEY, state)?,
+        ) else {
+            return Ok(None);
+        };
+
+        let mut range = Dictionary::new();
+        if location.as_signed_integer() != Some(NS_NOT_FOUND) {
+            range.insert("location".to_string(), location);
+        } else if self.options.leave_null_values {
+            range.insert(
+                "location".to_string(),
+                Value::String(NULL_OBJECT_REFERENCE_NAME.to_string()),
+            );
+        }
+        range.insert("length".to_string(), length);
+        Ok(Some(Value::Dictionary(range)))
+    }
+
+    fn ns_value_kind(
+        &self,
+        dict: &Dictionary,
+        state: &mut DecodeState,
+    ) -> Result<Option<ValueKind>, ConverterError> {
+        if let Some(tag) = self
+            .decode_field(dict, SPECIAL_KEY, state)?
+            .and_then(|tag| tag.as_unsigned_integer())
+        {
+            return Ok(ValueKind::from_special(tag));
+        }
+        for key in OBJC_TYPE_KEYS {
+            if let Some(objc_type) = self.raw_string_field(dict, key) {
+                return Ok(ValueKind::from_objc_type(objc_type));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns `count` numbers of a struct field. They're stored either as a
+    /// string like `{{0, 0}, {100, 200}}` or, in newer archives, as
+    /// little-endian floating point numbers in a data object.
+    fn raw_numbers_field(&self, dict: &Dictionary, key: &str, count: usize) -> Option<Vec<f64>> {
+        let numbers = match dict.get(key).and_then(|value| self.resolve_raw(value))? {
+            Value::String(s) => parse_numbers(s)?,
+            Value::Data(data) => parse_binary_numbers(data, count)?,
+            Value::Dictionary(data) => match data.get(NS_DATA_KEY)? {
+                Value::Data(data) => parse_binary_numbers(data, count)?,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        (numbers.len() == count).then_some(numbers)
+    }
+
+    /// Returns a string field without decoding it, so it isn't affected by
+    /// the string transform.
+    fn raw_string_field<'a>(&'a self, dict: &'a Dictionary, key: &str) -> Option<&'a str> {
+        dict.get(key)
+            .and_then(|value| self.resolve_raw(value))
+            .and_then(|value| value.as_string())
+    }
+}
+
+/// Parses all numbers of a struct string like `{{0, 0}, {100, 200}}`.
+fn parse_numbers(s: &str) -> Option<Vec<f64>> {
+    s.split(['{', '}', ','])
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+/// Reads `count` little-endian numbers from binary struct data. `CGFloat` is
+/// a double on 64-bit platforms and a float on 32-bit ones, so the size of
+/// the data tells which one was used.
+fn parse_binary_numbers(data: &[u8], count: usize) -> Option<Vec<f64>> {
+    if data.len() == count * 8 {
+        Some(
+            data.chunks_exact(8)
+                .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        )
+    } else if data.len() == count * 4 {
+        Some(
+            data.chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()).into())
+                .collect(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Builds a `{origin: {x, y}, size: {width, height}}` dictionary from the
+/// numbers of a rect.
+fn rect_dict(numbers: Vec<f64>) -> Value {
+    let (origin, size) = numbers.split_at(POINT_FIELDS.len());
+
+    let mut dict = Dictionary::new();
+    dict.insert(
+        "origin".to_string(),
+        Value::Dictionary(numbers_dict(&POINT_FIELDS, origin)),
+    );
+    dict.insert(
+        "size".to_string(),
+        Value::Dictionary(numbers_dict(&SIZE_FIELDS, size)),
+    );
+    Value::Dictionary(dict)
+}
+
+fn numbers_dict(names: &[&str], numbers: &[f64]) -> Dictionary {
+    let mut dict = Dictionary::new();
+    for (name, number) in names.iter().zip(numbers) {
+        dict.insert(name.to_string(), Value::Real(*number));
+    }
+    dict
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::*;
+    use plist::Value;
+
+    fn decode_value(fields: Vec<(&str, Value)>) -> Value {
+        decode_root(&converter(vec![
+            string("$null"),
+            object(2, fields),
+            class(&["NSValue", "NSObject"]),
+        ]))
+    }
+
+    fn reals(pairs: Vec<(&str, f64)>) -> Value {
+        dict(
+            pairs
+                .into_iter()
+                .map(|(name, n)| (name, Value::Real(n)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn edge_insets_decode_the_same_on_both_platforms() {
+        let expected = reals(vec![
+            ("top", 1.0),
+            ("left", 2.0),
+            ("bottom", 3.0),
+            ("right", 4.5),
+        ]);
+        for objc_type in ["{NSEdgeInsets=dddd}", "{UIEdgeInsets=dddd}"] {
+            let decoded = decode_value(vec![
+                ("NS.objctype", string(objc_type)),
+                ("NS.edgeinsetsval", string("{1, 2, 3, 4.5}")),
+            ]);
+            assert_eq!(decoded, expected, "{objc_type}");
+        }
+    }
+
+    #[test]
+    fn boxed_numbers_decode_into_bare_numbers() {
+        for number in [int(42), Value::Real(2.5)] {
+            let decoded = decode_root(&converter(vec![
+                string("$null"),
+                object(2, vec![("NS.value", uid(3))]),
+                class(&["NSValue", "NSObject"]),
+                number.clone(),
+            ]));
+            assert_eq!(decoded, number);
+        }
+    }
+
+    #[test]
+    fn not_found_range_location_is_null() {
+        let mut converter = converter(vec![
+            string("$null"),
+            object(
+                2,
+                vec![
+                    ("NS.special", int(4)),
+                    ("NS.rangeval.location", int(i64::MAX)),
+                    ("NS.rangeval.length", int(0)),
+                ],
+            ),
+            class(&["NSValue", "NSObject"]),
+        ]);
+        assert_eq!(decode_root(&converter), dict(vec![("length", int(0))]));
+
+        converter.set_leave_null_values(true);
+        assert_eq!(
+            decode_root(&converter),
+            dict(vec![("location", string("$null")), ("length", int(0))])
+        );
+    }
+
+    #[test]
+    fn legacy_points_are_recognized_by_their_type_encoding() {
+        for objc_type in ["{CGPoint=dd}", "{_NSPoint=ff}"] {
+            let decoded = decode_value(vec![
+                ("objCType", string(objc_type)),
+                ("NS.pointval", string("{3, -4.25}")),
+            ]);
+            assert_eq!(
+                decoded,
+                reals(vec![("x", 3.0), ("y", -4.25)]),
+                "{objc_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn special_value_subclass_decodes_like_ns_value() {
+        let fields = vec![("NS.special", int(1)), ("NS.pointval", string("{1, 2}"))];
+        let decoded = decode_root(&converter(vec![
+            string("$null"),
+            object(2, fields.clone()),
+            class(&["NSSpecialValue", "NSValue", "NSObject"]),
+        ]));
+        assert_eq!(decoded, decode_value(fields));
+        assert_eq!(decoded, reals(vec![("x", 1.0), ("y", 2.0)]));
+    }
+
+    #[test]
+    fn special_point_decodes_into_coordinates() {
+        let decoded = decode_value(vec![
+            ("NS.special", int(1)),
+            ("NS.pointval", string("{12, -3.5}")),
+        ]);
+        assert_eq!(decoded, reals(vec![("x", 12.0), ("y", -3.5)]));
+    }
+
+    #[test]
+    fn special_size_decodes_into_dimensions() {
+        let decoded = decode_value(vec![
+            ("NS.special", int(2)),
+            ("NS.sizeval", string("{100, 200.25}")),
+        ]);
+        assert_eq!(decoded, reals(vec![("width", 100.0), ("height", 200.25)]));
+    }
+
+    #[test]
+    fn special_rect_decodes_into_origin_and_size() {
+        let decoded = decode_value(vec![
+            ("NS.special", int(3)),
+            ("NS.rectval", string("{{0, 0.5}, {100, 200}}")),
+        ]);
+        assert_eq!(
+            decoded,
+            dict(vec![
+                ("origin", reals(vec![("x", 0.0), ("y", 0.5)])),
+                ("size", reals(vec![("width", 100.0), ("height", 200.0)])),
+            ])
+        );
+    }
+
+    #[test]
+    fn special_range_decodes_into_location_and_length() {
+        let decoded = decode_value(vec![
+            ("NS.special", int(4)),
+            ("NS.rangeval.location", int(3)),
+            ("NS.rangeval.length", int(7)),
+        ]);
+        assert_eq!(
+            decoded,
+            dict(vec![("location", int(3)), ("length", int(7))])
+        );
+    }
+
+    #[test]
+    fn binary_point_decodes_from_doubles() {
+        let bytes = [1.5f64, -2.0]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+        let decoded = decode_value(vec![
+            ("NS.special", int(1)),
+            ("NS.pointval", Value::Data(bytes)),
+        ]);
+        assert_eq!(decoded, reals(vec![("x", 1.5), ("y", -2.0)]));
+    }
+
+    #[test]
+    fn binary_rect_decodes_from_doubles() {
+        let bytes = [10.0f64, 20.0, 300.5, 400.0]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+        let decoded = decode_value(vec![
+            ("NS.special", int(3)),
+            ("NS.rectval", Value::Data(bytes)),
+        ]);
+        assert_eq!(
+            decoded,
+            dict(vec![
+                ("origin", reals(vec![("x", 10.0), ("y", 20.0)])),
+                ("size", reals(vec![("width", 300.5), ("height", 400.0)])),
+            ])
+        );
+    }
+}
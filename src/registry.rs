@@ -0,0 +1,291 @@
+//! A registry of known classes that are decoded with canonical field names.
+
+use crate::foundation::{as_seconds, NS_TIME_KEY};
+use crate::{Converter, ConverterError, DecodeState};
+use plist::{Dictionary, Value};
+
+/// A class with a well-known layout.
+pub(crate) struct KnownClass {
+    pub(crate) name: &'static str,
+    pub(crate) fields: &'static [KnownField],
+}
+
+/// A notable field of a known class.
+pub(crate) struct KnownField {
+    /// A canonical name of the field.
+    name: &'static str,
+    /// Archived keys that may hold the field, in order of preference.
+    keys: &'static [&'static str],
+    kind: FieldKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FieldKind {
+    Any,
+    /// A `NSDate` or seconds since the Cocoa reference date.
+    Date,
+}
+
+const fn field(name: &'static str, keys: &'static [&'static str]) -> KnownField {
+    KnownField {
+        name,
+        keys,
+        kind: FieldKind::Any,
+    }
+}
+
+const fn date_field(name: &'static str, keys: &'static [&'static str]) -> KnownField {
+    KnownField {
+        name,
+        keys,
+        kind: FieldKind::Date,
+    }
+}
+
+const KNOWN_CLASSES: &[KnownClass] = &[
+    // WebKit session state
+    KnownClass {
+        name: "WKBackForwardListItem",
+        fields: &[
+            field("url", &["URL", "url", "WKBackForwardListItemURL"]),
+            field(
+                "originalURL",
+                &["originalURL", "WKBackForwardListItemOriginalURL"],
+            ),
+            field("title", &["title", "WKBackForwardListItemTitle"]),
+        ],
+    },
+    KnownClass {
+        name: "WKBackForwardList",
+        fields: &[
+            field("items", &["entries", "items", "WKBackForwardListItems"]),
+            field(
+                "currentIndex",
+                &["currentIndex", "WKBackForwardListCurrentIndex"],
+            ),
+        ],
+    },
+    // GameKit leaderboards
+    KnownClass {
+        name: "GKScore",
+        fields: &[
+            field("value", &["value"]),
+            field("formattedValue", &["formattedValue"]),
+            field("rank", &["rank"]),
+            field("playerID", &["playerID", "player"]),
+            date_field("date", &["date"]),
+            field(
+                "leaderboardIdentifier",
+                &["leaderboardIdentifier", "category"],
+            ),
+            field("context", &["context"]),
+        ],
+    },
+    KnownClass {
+        name: "GKLeaderboard",
+        fields: &[
+            field(
+                "identifier",
+                &["identifier", "baseLeaderboardID", "category"],
+            ),
+            field("title", &["title"]),
+            field("groupIdentifier", &["groupIdentifier"]),
+            date_field("startDate", &["startDate"]),
+            field("maxRange", &["maxRange"]),
+        ],
+    },
+    // Instruments messages sent over DTXConnectionServices. Their payload
+    // is a plist that is archived along with the message
+    KnownClass {
+        name: "DTTapMessage",
+        fields: DT_TAP_MESSAGE_FIELDS,
+    },
+    KnownClass {
+        name: "DTActivityTraceTapMessage",
+        fields: DT_TAP_MESSAGE_FIELDS,
+    },
+    KnownClass {
+        name: "DTKTraceTapMessage",
+        fields: DT_TAP_MESSAGE_FIELDS,
+    },
+    KnownClass {
+        name: "DTSysmonTapMessage",
+        fields: DT_TAP_MESSAGE_FIELDS,
+    },
+    KnownClass {
+        name: "DTTapHeartbeatMessage",
+        fields: DT_TAP_MESSAGE_FIELDS,
+    },
+    KnownClass {
+        name: "DTTapStatusMessage",
+        fields: DT_TAP_MESSAGE_FIELDS,
+    },
+];
+
+/// Fields of Instruments tap messages, which all share the layout of
+/// `DTTapMessage`.
+const DT_TAP_MESSAGE_FIELDS: &[KnownField] = &[field("plist", &["DTTapMessagePlist"])];
+
+/// Looks up a known class by its name.
+pub(crate) fn known_class(name: &str) -> Option<&'static KnownClass> {
+    KNOWN_CLASSES.iter().find(|class| class.name == name)
+}
+
+impl Converter {
+    /// Decodes an object of a known class. Its notable fields are renamed to
+    /// canonical names, other fields are kept as is.
+    pub(crate) fn decode_known_class(
+        &self,
+        object_ref: u64,
+        dict: &Dictionary,
+        known: &KnownClass,
+        state: &mut DecodeState,
+    ) -> Result<Value, ConverterError> {
+        let Value::Dictionary(decoded) = self.decode_custom_class(object_ref, dict, state)? else {
+            return Err(ConverterError::invalid_encoding(object_ref));
+        };
+
+        // The archived key of each field that's present
+        let field_keys: Vec<Option<&str>> = known
+            .fields
+            .iter()
+            .map(|field| {
+                field
+                    .keys
+                    .iter()
+                    .copied()
+                    .find(|key| decoded.contains_key(key))
+            })
+            .collect();
+
+        let mut field_values: Vec<Option<Value>> = vec![None; known.fields.len()];
+        let mut result = Dictionary::new();
+        for (key, value) in decoded {
+            match field_keys
+                .iter()
+                .position(|field_key| *field_key == Some(&key))
+            {
+                Some(index) => field_values[index] = Some(value),
+                None => {
+                    result.insert(key, value);
+                }
+            }
+        }
+
+        // Notable fields follow the other ones in the order of the registry
+        for (field, value) in known.fields.iter().zip(field_values) {
+            let Some(value) = value else {
+                continue;
+            };
+            let value = match field.kind {
+                FieldKind::Any => value,
+                FieldKind::Date => self.date_value(value),
+            };
+            result.insert(field.name.to_string(), value);
+        }
+        Ok(Value::Dictionary(result))
+    }
+
+    /// Converts a decoded `NSDate` or a number of seconds into a date, see
+    /// [Converter::date_from_seconds]. Other values are returned as is.
+    fn date_value(&self, value: Value) -> Value {
+        let seconds = match &value {
+            Value::Dictionary(dict) => dict.get(NS_TIME_KEY).and_then(as_seconds),
+            value => as_seconds(value),
+        };
+        seconds
+            .and_then(|seconds| self.date_from_seconds(seconds))
+            .unwrap_or(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::*;
+    use plist::Value;
+
+    fn classes(names: &[&str]) -> Value {
+        Value::Array(names.iter().map(|name| string(name)).collect())
+    }
+
+    #[test]
+    fn web_kit_items_surface_url_and_title() {
+        let decoded = decode_root(&converter(vec![
+            string("$null"),
+            object(
+                2,
+                vec![
+                    ("title", uid(4)),
+                    ("URL", uid(3)),
+                    ("WKBackForwardListItemIdentifier", int(7)),
+                ],
+            ),
+            class(&["WKBackForwardListItem", "NSObject"]),
+            string("https://example.com/"),
+            string("Example Domain"),
+        ]));
+        assert_eq!(
+            decoded,
+            dict(vec![
+                ("$classes", classes(&["WKBackForwardListItem", "NSObject"])),
+                ("WKBackForwardListItemIdentifier", int(7)),
+                ("url", string("https://example.com/")),
+                ("title", string("Example Domain")),
+            ])
+        );
+    }
+
+    #[test]
+    fn game_kit_scores_decode_numbers_and_dates() {
+        let decoded = decode_root(&converter(vec![
+            string("$null"),
+            object(
+                2,
+                vec![
+                    ("value", int(1000)),
+                    ("rank", int(3)),
+                    ("playerID", uid(3)),
+                    ("date", uid(4)),
+                ],
+            ),
+            class(&["GKScore", "NSObject"]),
+            string("G:123"),
+            object(5, vec![("NS.time", Value::Real(86400.0))]),
+            class(&["NSDate", "NSObject"]),
+        ]));
+        let date = std::time::UNIX_EPOCH + std::time::Duration::from_secs(978_307_200 + 86400);
+        assert_eq!(
+            decoded,
+            dict(vec![
+                ("$classes", classes(&["GKScore", "NSObject"])),
+                ("value", int(1000)),
+                ("rank", int(3)),
+                ("playerID", string("G:123")),
+                ("date", Value::Date(date.into())),
+            ])
+        );
+    }
+
+    #[test]
+    fn instruments_tap_messages_decode_their_arguments() {
+        let decoded = decode_root(&converter(vec![
+            string("$null"),
+            object(2, vec![("DTTapMessagePlist", uid(3))]),
+            class(&["DTActivityTraceTapMessage", "DTTapMessage", "NSObject"]),
+            array(4, &[5, 6]),
+            class(&["NSArray", "NSObject"]),
+            string("kdebug"),
+            int(42),
+        ]));
+        assert_eq!(
+            decoded,
+            dict(vec![
+                (
+                    "$classes",
+                    classes(&["DTActivityTraceTapMessage", "DTTapMessage", "NSObject"]),
+                ),
+                ("plist", Value::Array(vec![string("kdebug"), int(42)])),
+            ])
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Statistics about the contents of an archive.
+
+use crate::{with_stack_guard, Converter};
+use plist::Value;
+use std::collections::HashMap;
+
+/// Size of a number, a date or a reference.
+const SCALAR_SIZE: usize = 8;
+
+/// A number of objects of one type and their estimated size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TypeStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+impl TypeStats {
+    fn add(&mut self, bytes: usize) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// A breakdown of `$objects` by type, see [Converter::objects_memory_report].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub strings: TypeStats,
+    pub data: TypeStats,
+    /// Integers, reals and booleans.
+    pub numbers: TypeStats,
+    /// Dictionaries and arrays. Only the size of their own entries is
+    /// counted, not the size of the objects they reference.
+    pub containers: TypeStats,
+    /// Dates and references.
+    pub other: TypeStats,
+}
+
+impl MemoryReport {
+    /// Returns the total number of objects.
+    pub fn total_count(&self) -> usize {
+        self.stats().iter().map(|stats| stats.count).sum()
+    }
+
+    /// Returns the estimated size of all objects in bytes.
+    pub fn total_bytes(&self) -> usize {
+        self.stats().iter().map(|stats| stats.bytes).sum()
+    }
+
+    fn stats(&self) -> [TypeStats; 5] {
+        [
+            self.strings,
+            self.data,
+            self.numbers,
+            self.containers,
+            self.other,
+        ]
+    }
+}
+
+impl Converter {
+    /// Returns the estimated size in bytes of an entry of `$objects`, or
+    /// `None` if there's no object at the index.
+    pub fn object_size_bytes(&self, index: u64) -> Option<usize> {
+        self.get_object(index).map(value_size_bytes)
+    }
+
+    /// Returns counts and estimated sizes of `$objects` entries grouped by
+    /// their type.
+    pub fn objects_memory_report(&self) -> MemoryReport {
+        let mut report = MemoryReport::default();
+        for object in &self.objects {
+            let stats = match object {
+                Value::String(_) => &mut report.strings,
+                Value::Data(_) => &mut report.data,
+                Value::Integer(_) | Value::Real(_) | Value::Boolean(_) => &mut report.numbers,
+                Value::Array(_) | Value::Dictionary(_) => &mut report.containers,
+                _ => &mut report.other,
+            };
+            stats.add(value_size_bytes(object));
+        }
+        report
+    }
+
+    /// Counts how many entries of `$objects` are instances of each class.
+    /// Only the concrete class of an object is counted, not its superclasses.
+    pub fn class_name_histogram(&self) -> HashMap<String, usize> {
+        let mut histogram = HashMap::new();
+        for object in &self.objects {
+            let Some(name) = self
+                .raw_class_names(object)
+                .and_then(|names| names.first().copied())
+            else {
+                continue;
+            };
+            *histogram.entry(name.to_string()).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// Counts how many entries of `$objects` are instances of each full class
+    /// chain, e.g. `["NSMutableArray", "NSArray", "NSObject"]`. Chains are
+    /// ordered by their first occurrence.
+    pub fn class_manifest(&self) -> Vec<(Vec<String>, usize)> {
+        let mut manifest: Vec<(Vec<String>, usize)> = Vec::new();
+        let mut positions: HashMap<Vec<&str>, usize> = HashMap::new();
+        for object in &self.objects {
+            let Some(names) = self.raw_class_names(object) else {
+                continue;
+            };
+            match positions.get(&names) {
+                Some(&position) => manifest[position].1 += 1,
+                None => {
+                    let chain = names.iter().map(|name| name.to_string()).collect();
+                    positions.insert(names, manifest.len());
+                    manifest.push((chain, 1));
+                }
+            }
+        }
+        manifest
+    }
+
+    /// Returns the class chain of a raw object, or `None` if it isn't an
+    /// object of a class.
+    fn raw_class_names(&self, object: &Value) -> Option<Vec<&str>> {
+        let class_reference = object
+            .as_dictionary()
+            .and_then(|dict| dict.get(&self.reserved_keys.class))
+            .and_then(|class| self.as_reference(class))?;
+        self.get_class_names(&class_reference).ok()
+    }
+}
+
+/// Estimates the size of a raw value. References count as a fixed size.
+fn value_size_bytes(value: &Value) -> usize {
+    with_stack_guard(|| value_size_bytes_unguarded(value))
+}
+
+fn value_size_bytes_unguarded(value: &Value) -> usize {
+    match value {
+        Value::String(s) => s.len(),
+        Value::Data(data) => data.len(),
+        Value::Boolean(_) => 1,
+        Value::Array(array) => array.iter().map(value_size_bytes).sum(),
+        Value::Dictionary(dict) => dict
+            .iter()
+            .map(|(key, value)| key.len() + value_size_bytes(value))
+            .sum(),
+        _ => SCALAR_SIZE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn memory_report_groups_objects_by_type() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 4]),
+            class(&["NSArray", "NSObject"]),
+            string("Alice"),
+            Value::Data(vec![1, 2, 3]),
+            int(42),
+            Value::Real(1.5),
+            Value::Boolean(true),
+            Value::Date(std::time::SystemTime::UNIX_EPOCH.into()),
+        ]);
+        let report = converter.objects_memory_report();
+        assert_eq!(
+            report.strings,
+            TypeStats {
+                count: 2,
+                bytes: 10
+            }
+        );
+        assert_eq!(report.data, TypeStats { count: 1, bytes: 3 });
+        assert_eq!(
+            report.numbers,
+            TypeStats {
+                count: 3,
+                bytes: 17
+            }
+        );
+        // `$class` and `NS.objects` keys with three references, and
+        // `$classname` and `$classes` keys with three class names
+        assert_eq!(
+            report.containers,
+            TypeStats {
+                count: 2,
+                bytes: 80
+            }
+        );
+        assert_eq!(report.other, TypeStats { count: 1, bytes: 8 });
+        assert_eq!(report.total_count(), 9);
+        assert_eq!(report.total_bytes(), 118);
+    }
+
+    fn zoo() -> crate::Converter {
+        converter(vec![
+            string("$null"),
+            array(2, &[3, 5, 6]),
+            class(&["NSMutableArray", "NSArray", "NSObject"]),
+            object(4, vec![]),
+            class(&["Person", "NSObject"]),
+            object(4, vec![]),
+            object(7, vec![]),
+            class(&["Pet", "Animal", "NSObject"]),
+        ])
+    }
+
+    #[test]
+    fn class_name_histogram_counts_concrete_classes() {
+        let histogram = zoo().class_name_histogram();
+        assert_eq!(
+            histogram,
+            HashMap::from([
+                ("NSMutableArray".to_string(), 1),
+                ("Person".to_string(), 2),
+                ("Pet".to_string(), 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn class_manifest_counts_full_class_chains() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 4]),
+            class(&["NSArray", "NSObject"]),
+            object(5, vec![]),
+            object(5, vec![]),
+            class(&["Pet", "Animal", "NSObject"]),
+        ]);
+        let chain = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(
+            converter.class_manifest(),
+            vec![
+                (chain(&["NSArray", "NSObject"]), 1),
+                (chain(&["Pet", "Animal", "NSObject"]), 2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Validation of decoded values against a JSON schema.
+
+use crate::{json::to_json_value, ConverterError};
+use plist::Value;
+
+/// A compiled JSON schema, see [jsonschema::validator_for].
+pub type Schema = jsonschema::Validator;
+
+/// Validates a decoded value against a JSON schema.
+///
+/// The value is converted to JSON with [to_json_value] first. If it doesn't
+/// match, the returned error lists every violation along with its location.
+pub fn validate_output(value: &Value, schema: &Schema) -> Result<(), ConverterError> {
+    let instance = to_json_value(value);
+    let errors: Vec<String> = schema
+        .iter_errors(&instance)
+        .map(|error| format!("{}: {}", error.instance_path(), error))
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConverterError::SchemaValidation(errors.join("; ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use serde_json::json;
+
+    #[test]
+    fn decoded_fixture_is_validated_against_a_schema() {
+        let converter = converter(vec![
+            string("$null"),
+            array(2, &[3, 4]),
+            class(&["NSArray", "NSObject"]),
+            string("Alice"),
+            string("Bob"),
+        ]);
+        let decoded = converter.decode().unwrap();
+
+        let schema = jsonschema::validator_for(&json!({
+            "type": "object",
+            "required": ["root"],
+            "properties": {
+                "root": {"type": "array", "items": {"type": "string"}}
+            }
+        }))
+        .unwrap();
+        assert!(validate_output(&decoded, &schema).is_ok());
+
+        let schema = jsonschema::validator_for(&json!({
+            "type": "object",
+            "properties": {"root": {"type": "array", "maxItems": 1}}
+        }))
+        .unwrap();
+        assert!(matches!(
+            validate_output(&decoded, &schema),
+            Err(ConverterError::SchemaValidation(message)) if message.starts_with("/root")
+        ));
+    }
+}
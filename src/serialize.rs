@@ -0,0 +1,118 @@
+//! Serialization of decoded values with serde.
+
+use crate::with_stack_guard;
+use base64::Engine;
+use plist::Value;
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+
+/// A key of the map that a uid is serialized into.
+const UID_KEY: &str = "CF$UID";
+
+/// A decoded value that implements [Serialize], e.g. to write the output of
+/// [crate::Converter::decode] with any serde data format.
+///
+/// Unlike the serde implementation of [plist::Value] itself, it only uses
+/// types that every format supports. The mapping is stable:
+/// - a dictionary becomes a map and an array becomes a sequence;
+/// - a string, a boolean or a number becomes the same type, integers keep
+///   their sign;
+/// - data becomes a base64 encoded string;
+/// - a date becomes an RFC 3339 string, e.g. `2001-01-01T00:00:00Z`;
+/// - a uid becomes a `{"CF$UID": <uid>}` map.
+///
+/// This matches [crate::to_json_value], except that non-finite real numbers
+/// are left to the format to handle.
+#[derive(Clone, Copy, Debug)]
+pub struct SerializableValue<'a>(pub &'a Value);
+
+impl Serialize for SerializableValue<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        with_stack_guard(|| self.serialize_unguarded(serializer))
+    }
+}
+
+impl SerializableValue<'_> {
+    fn serialize_unguarded<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Array(array) => {
+                let mut seq = serializer.serialize_seq(Some(array.len()))?;
+                for element in array {
+                    seq.serialize_element(&SerializableValue(element))?;
+                }
+                seq.end()
+            }
+            Value::Dictionary(dict) => {
+                let mut map = serializer.serialize_map(Some(dict.len()))?;
+                for (key, value) in dict {
+                    map.serialize_entry(key, &SerializableValue(value))?;
+                }
+                map.end()
+            }
+            Value::Boolean(b) => serializer.serialize_bool(*b),
+            Value::Data(data) => {
+                serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data))
+            }
+            Value::Date(date) => serializer.serialize_str(&date.to_xml_format()),
+            Value::Real(real) => serializer.serialize_f64(*real),
+            Value::Integer(integer) => match integer.as_signed() {
+                Some(signed) => serializer.serialize_i64(signed),
+                None => serializer.serialize_u64(integer.as_unsigned().unwrap_or_default()),
+            },
+            Value::String(s) => serializer.serialize_str(s),
+            Value::Uid(uid) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(UID_KEY, &uid.get())?;
+                map.end()
+            }
+            _ => serializer.serialize_unit(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use plist::Uid;
+    use serde_json::json;
+
+    #[test]
+    fn decoded_scalars_serialize_to_json() {
+        let mut decoded = decode_root(&converter(vec![
+            string("$null"),
+            object(
+                2,
+                vec![
+                    ("string", uid(3)),
+                    ("integer", int(-7)),
+                    ("real", Value::Real(1.5)),
+                    ("boolean", Value::Boolean(true)),
+                    ("data", Value::Data(b"abc".to_vec())),
+                    ("date", uid(4)),
+                ],
+            ),
+            class(&["Scalars", "NSObject"]),
+            string("text"),
+            object(5, vec![("NS.time", Value::Real(0.0))]),
+            class(&["NSDate", "NSObject"]),
+        ]));
+        decoded
+            .as_dictionary_mut()
+            .unwrap()
+            .insert("uid".to_string(), Value::Uid(Uid::new(9)));
+
+        assert_eq!(
+            serde_json::to_value(SerializableValue(&decoded)).unwrap(),
+            json!({
+                "$classes": ["Scalars", "NSObject"],
+                "string": "text",
+                "integer": -7,
+                "real": 1.5,
+                "boolean": true,
+                "data": "YWJj",
+                "date": "2001-01-01T00:00:00Z",
+                "uid": {"CF$UID": 9},
+            })
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Decoding of objects archived from Swift classes.
+
+use crate::{Converter, ConverterError, DecodeState};
+use plist::{Dictionary, Value};
+
+/// A key that holds the module and the name of a Swift class, see
+/// [crate::ConverterOptions::split_swift_class_module].
+const SWIFT_CLASS_KEY: &str = "$class";
+/// The module of the Swift standard library, which mangled names abbreviate
+/// to `s`.
+const SWIFT_STDLIB_MODULE: &str = "Swift";
+
+/// Checks if a class name belongs to a Swift class. Those are either mangled,
+/// e.g. `_TtC5MyApp6Person`, or qualified with a module name, e.g.
+/// `MyApp.Person`.
+pub(crate) fn is_swift_class(name: &str) -> bool {
+    name.starts_with("_Tt") || name.contains('.')
+}
+
+/// Splits a Swift class name into its module and its name, e.g.
+/// `_TtC5MyApp6Person` or `MyApp.Person` into `MyApp` and `Person`. Names of
+/// nested types are joined with dots.
+fn split_swift_class_name(name: &str) -> Option<(String, String)> {
+    let Some(mangled) = name.strip_prefix("_Tt") else {
+        let (module, name) = name.split_once('.')?;
+        return Some((module.to_string(), name.to_string()));
+    };
+
+    // A kind (class, struct or enum) for each level of nesting
+    let nesting = mangled
+        .chars()
+        .take_while(|c| matches!(c, 'C' | 'V' | 'O'))
+        .count();
+    let mut rest = &mangled[nesting..];
+    let module = match rest.strip_prefix('s') {
+        Some(after) => {
+            rest = after;
+            SWIFT_STDLIB_MODULE.to_string()
+        }
+        None => take_identifier(&mut rest)?.to_string(),
+    };
+    let mut names = Vec::with_capacity(nesting);
+    for _ in 0..nesting {
+        names.push(take_identifier(&mut rest)?);
+    }
+    if nesting == 0 || !rest.is_empty() {
+        return None;
+    }
+    Some((module, names.join(".")))
+}
+
+/// Takes a length prefixed identifier, e.g. `6Person`, off a mangled name.
+fn take_identifier<'a>(mangled: &mut &'a str) -> Option<&'a str> {
+    let digits = mangled.chars().take_while(char::is_ascii_digit).count();
+    let len: usize = mangled[..digits].parse().ok()?;
+    let end = digits.checked_add(len)?;
+    let identifier = mangled.get(digits..end)?;
+    *mangled = &mangled[end..];
+    Some(identifier)
+}
+
+impl Converter {
+    /// Decodes an object of a Swift class into a dictionary of its fields
+    /// without a `$classes` key. See also
+    /// [crate::ConverterOptions::split_swift_class_module].
+    ///
+    /// Unkeyed containers are archived with generated `$0`, `$1`, ... keys,
+    /// so an object that only has such keys is decoded into an array. Nested
+    /// containers, including the `super` one, become nested values.
+    pub(crate) fn decode_swift_class(
+        &self,
+        object_ref: u64,
+        dict: &Dictionary,
+        state: &mut DecodeState,
+    ) -> Result<Value, ConverterError> {
+        let Value::Dictionary(decoded) = self.decode_custom_class(object_ref, dict, state)? else {
+            return Err(ConverterError::invalid_encoding(object_ref));
+        };
+        let class_name = decoded
+            .get("$classes")
+            .filter(|_| self.options.split_swift_class_module)
+            .and_then(|classes| classes.as_array())
+            .and_then(|classes| classes.first())
+            .and_then(|name| name.as_string())
+            .and_then(split_swift_class_name);
+        let mut decoded: Dictionary = decoded
+            .into_iter()
+            .filter(|(key, _)| key != "$classes")
+            .collect();
+
+        let generated_keys: Vec<String> = (0..decoded.len()).map(|i| format!("${i}")).collect();
+        if decoded.is_empty() || !generated_keys.iter().all(|key| decoded.contains_key(key)) {
+            let Some((module, name)) = class_name else {
+                return Ok(Value::Dictionary(decoded));
+            };
+            let mut class = Dictionary::new();
+            class.insert("module".to_string(), Value::String(module));
+            class.insert("name".to_string(), Value::String(name));
+            let mut with_class = Dictionary::new();
+            with_class.insert(SWIFT_CLASS_KEY.to_string(), Value::Dictionary(class));
+            with_class.extend(decoded);
+            return Ok(Value::Dictionary(with_class));
+        }
+        let elements = generated_keys
+            .iter()
+            .filter_map(|key| decoded.remove(key))
+            .collect();
+        Ok(Value::Array(elements))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::*;
+    use plist::Value;
+
+    #[test]
+    fn codable_object_decodes_into_nested_dictionaries() {
+        let converter = converter(vec![
+            string("$null"),
+            object(
+                2,
+                vec![("name", uid(3)), ("super", uid(4)), ("tags", uid(6))],
+            ),
+            class(&["_TtC5MyApp6Person", "_TtC5MyApp4Base", "NSObject"]),
+            string("Alice"),
+            object(5, vec![("id", int(7))]),
+            class(&["_TtC5MyApp4Base", "NSObject"]),
+            object(7, vec![("$0", uid(8)), ("$1", uid(9))]),
+            class(&["MyApp.Tags", "NSObject"]),
+            string("admin"),
+            string("staff"),
+        ]);
+        assert_eq!(
+            decode_root(&converter),
+            dict(vec![
+                ("name", string("Alice")),
+                ("super", dict(vec![("id", int(7))])),
+                ("tags", Value::Array(vec![string("admin"), string("staff")])),
+            ])
+        );
+    }
+
+    #[test]
+    fn split_swift_class_module_separates_module_and_name() {
+        let options = crate::ConverterOptions {
+            split_swift_class_module: true,
+            ..Default::default()
+        };
+        let converter = converter_with_options(
+            vec![
+                string("$null"),
+                object(2, vec![("name", uid(3))]),
+                class(&["_TtC5MyApp6Person", "NSObject"]),
+                string("Alice"),
+            ],
+            options,
+        );
+        assert_eq!(
+            decode_root(&converter),
+            dict(vec![
+                (
+                    "$class",
+                    dict(vec![
+                        ("module", string("MyApp")),
+                        ("name", string("Person"))
+                    ]),
+                ),
+                ("name", string("Alice")),
+            ])
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! A typed tree representation of a decoded archive.
+
+use crate::{
+    with_stack_guard, Converter, ConverterError, CLASS_NAME_KEY_NAME, NULL_OBJECT_REFERENCE_NAME,
+};
+use plist::{Date, Dictionary, Integer, Uid, Value};
+use std::collections::HashSet;
+
+/// A node of a decoded archive.
+///
+/// Unlike [plist::Value] it keeps objects of custom classes apart from
+/// dictionaries and allows dictionary keys of any type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    Null,
+    Bool(bool),
+    Int(Integer),
+    Real(f64),
+    String(String),
+    Data(Vec<u8>),
+    Date(Date),
+    Array(Vec<Node>),
+    /// A dictionary as a list of key-value pairs.
+    Dict(Vec<(Node, Node)>),
+    /// An object of a class that isn't a native collection.
+    Object {
+        /// The name of the object class.
+        class: String,
+        fields: Vec<(String, Node)>,
+    },
+}
+
+impl From<Value> for Node {
+    fn from(value: Value) -> Self {
+        with_stack_guard(|| Self::from_unguarded(value))
+    }
+}
+
+impl Node {
+    fn from_unguarded(value: Value) -> Self {
+        match value {
+            Value::Boolean(b) => Self::Bool(b),
+            Value::Integer(i) => Self::Int(i),
+            Value::Real(r) => Self::Real(r),
+            Value::String(s) => Self::String(s),
+            Value::Data(d) => Self::Data(d),
+            Value::Date(d) => Self::Date(d),
+            Value::Uid(uid) => Self::Int(uid.get().into()),
+            Value::Array(array) => Self::Array(array.into_iter().map(Self::from).collect()),
+            Value::Dictionary(dict) => Self::Dict(
+                dict.into_iter()
+                    .map(|(key, value)| (Self::String(key), Self::from(value)))
+                    .collect(),
+            ),
+            _ => Self::Null,
+        }
+    }
+}
+
+impl Converter {
+    /// Decodes a NSKeyedArchiver encoded plist into a tree of [Node]s.
+    ///
+    /// The root is a [Node::Dict] of the top level keys, or a [Node::Array]
+    /// if `$top` was an array. `$null` values are kept as [Node::Null].
+    pub fn decode_tree(&self) -> Result<Node, ConverterError> {
+        if let Some(passthrough) = &self.passthrough {
+            return Ok(passthrough.clone().into());
+        }
+
+        let mut parents = HashSet::new();
+        let mut top = Vec::with_capacity(self.top.len());
+        for (key, value) in &self.top {
+            let uid = self.expect_uid(value, key)?;
+            let node = self
+                .tree_object(&uid, &mut parents)
+                .map_err(|e| e.at(key))?;
+            top.push((Node::String(key.clone()), node));
+        }
+        if self.top_is_array {
+            return Ok(Node::Array(top.into_iter().map(|(_, node)| node).collect()));
+        }
+        Ok(Node::Dict(top))
+    }
+
+    fn tree_object(&self, uid: &Uid, parents: &mut HashSet<u64>) -> Result<Node, ConverterError> {
+        with_stack_guard(|| self.tree_object_unguarded(uid, parents))
+    }
+
+    fn tree_object_unguarded(
+        &self,
+        uid: &Uid,
+        parents: &mut HashSet<u64>,
+    ) -> Result<Node, ConverterError> {
+        let object_ref = uid.get();
+        if object_ref == 0 {
+            return Ok(Node::Null);
+        }
+
+        if parents.contains(&object_ref) {
+            if self.options.reject_circular_references {
+                return Err(ConverterError::CyclicReference(object_ref));
+            }
+            return Ok(Node::String(format!(
+                "circular reference for uid #{object_ref}"
+            )));
+        }
+
+        let Some(object) = self.get_object(object_ref) else {
+            return Err(ConverterError::InvalidObjectReference(object_ref));
+        };
+
+        if object.as_string() == Some(NULL_OBJECT_REFERENCE_NAME) {
+            return Ok(Node::Null);
+        }
+
+        if !self.is_container(object) {
+            return Ok(object.clone().into());
+        }
+
+        if let Some(max_depth) = self.options.max_depth {
+            if parents.len() >= max_depth {
+                return Err(ConverterError::MaxDepthExceeded(max_depth));
+            }
+        }
+        parents.insert(object_ref);
+        let node = self.tree_container(object_ref, object, parents);
+        parents.remove(&object_ref);
+        node
+    }
+
+    fn tree_container(
+        &self,
+        object_ref: u64,
+        object: &Value,
+        parents: &mut HashSet<u64>,
+    ) -> Result<Node, ConverterError> {
+        let Some(dict) = object.as_dictionary() else {
+            return Err(ConverterError::invalid_encoding(object_ref));
+        };
+        let class_names = self.object_class_names(object_ref, dict)?;
+        self.check_class_allowed(&class_names)?;
+        let Some(class) = class_names.first().copied() else {
+            return Err(ConverterError::invalid_encoding(object_ref));
+        };
+
+        if !self.options.treat_all_as_classes {
+            match class {
+                "NSMutableDictionary" | "NSDictionary" | "__NSCFDictionary" => {
+                    let keys =
+                        self.tree_references(object_ref, dict, &self.reserved_keys.keys, parents)?;
+                    let values = self.tree_references(
+                        object_ref,
+                        dict,
+                        &self.reserved_keys.objects,
+                        parents,
+                    )?;
+                    if keys.len() != values.len() {
+                        return Err(ConverterError::invalid_encoding(object_ref));
+                    }
+                    return Ok(Node::Dict(keys.into_iter().zip(values).collect()));
+                }
+                "NSMutableArray"
+                | "NSArray"
+                | "__NSCFArray"
+                | "NSMutableSet"
+                | "NSSet"
+                | "NSMutableOrderedSet"
+                | "NSOrderedSet" => {
+                    let elements = self.tree_references(
+                        object_ref,
+                        dict,
+                        &self.reserved_keys.objects,
+                        parents,
+                    )?;
+                    return Ok(Node::Array(elements));
+                }
+                _ => {}
+            }
+        }
+
+        let mut fields = Vec::with_capacity(dict.len());
+        for (key, value) in dict {
+            if *key == self.reserved_keys.class
+                || (key == CLASS_NAME_KEY_NAME && !dict.contains_key(&self.reserved_keys.class))
+            {
+                continue;
+            }
+            let node = self.tree_field(value, parents).map_err(|e| e.at(key))?;
+            fields.push((key.clone(), node));
+        }
+        Ok(Node::Object {
+            class: class.to_string(),
+            fields,
+        })
+    }
+
+    fn tree_references(
+        &self,
+        object_ref: u64,
+        dict: &Dictionary,
+        key: &str,
+        parents: &mut HashSet<u64>,
+    ) -> Result<Vec<Node>, ConverterError> {
+        let Some(references) = dict.get(key).and_then(|refs| refs.as_array()) else {
+            return Err(ConverterError::invalid_encoding(object_ref));
+        };
+        self.check_collection_len(object_ref, references.len())?;
+        let mut nodes = Vec::with_capacity(references.len());
+        for (index, reference) in references.iter().enumerate() {
+            let uid = self.expect_uid(reference, key)?;
+            let node = self
+                .tree_object(&uid, parents)
+                .map_err(|e| e.at(&format!("{key}[{index}]")))?;
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    fn tree_field(
+        &self,
+        value: &Value,
+        parents: &mut HashSet<u64>,
+    ) -> Result<Node, ConverterError> {
+        with_stack_guard(|| self.tree_field_unguarded(value, parents))
+    }
+
+    fn tree_field_unguarded(
+        &self,
+        value: &Value,
+        parents: &mut HashSet<u64>,
+    ) -> Result<Node, ConverterError> {
+        match value {
+            Value::Uid(uid) => self.tree_object(uid, parents),
+            Value::Array(array) => {
+                let mut nodes = Vec::with_capacity(array.len());
+                for (index, element) in array.iter().enumerate() {
+                    let node = self
+                        .tree_field(element, parents)
+                        .map_err(|e| e.at(&format!("[{index}]")))?;
+                    nodes.push(node);
+                }
+                Ok(Node::Array(nodes))
+            }
+            _ => Ok(value.clone().into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+
+    #[test]
+    fn decode_tree_keeps_objects_apart_from_dictionaries() {
+        let converter = converter(vec![
+            string("$null"),
+            dictionary(2, &[3, 4], &[5, 6]),
+            class(&["NSDictionary", "NSObject"]),
+            string("items"),
+            string("owner"),
+            array(7, &[8, 0]),
+            object(9, vec![("name", uid(10)), ("age", int(3))]),
+            class(&["NSArray", "NSObject"]),
+            int(1),
+            class(&["Person", "NSObject"]),
+            string("Alice"),
+        ]);
+        let string = |s: &str| Node::String(s.to_string());
+        assert_eq!(
+            converter.decode_tree().unwrap(),
+            Node::Dict(vec![(
+                string("root"),
+                Node::Dict(vec![
+                    (
+                        string("items"),
+                        Node::Array(vec![Node::Int(1.into()), Node::Null])
+                    ),
+                    (
+                        string("owner"),
+                        Node::Object {
+                            class: "Person".to_string(),
+                            fields: vec![
+                                ("name".to_string(), string("Alice")),
+                                ("age".to_string(), Node::Int(3.into())),
+                            ],
+                        }
+                    ),
+                ])
+            )])
+        );
+    }
+}
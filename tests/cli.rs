@@ -0,0 +1,113 @@
+use nskeyedarchiver_converter::plist::{Dictionary, Uid, Value};
+use std::path::PathBuf;
+use std::process::Command;
+
+fn dict(pairs: Vec<(&str, Value)>) -> Value {
+    let mut dict = Dictionary::new();
+    for (key, value) in pairs {
+        dict.insert(key.to_string(), value);
+    }
+    Value::Dictionary(dict)
+}
+
+/// Writes an archive with a `root` array of two strings and returns its path.
+fn write_archive(name: &str) -> PathBuf {
+    let uid = |index| Value::Uid(Uid::new(index));
+    let archive = dict(vec![
+        ("$version", Value::Integer(100000.into())),
+        ("$archiver", Value::String("NSKeyedArchiver".to_string())),
+        ("$top", dict(vec![("root", uid(1))])),
+        (
+            "$objects",
+            Value::Array(vec![
+                Value::String("$null".to_string()),
+                dict(vec![
+                    ("$class", uid(2)),
+                    ("NS.objects", Value::Array(vec![uid(3), uid(4)])),
+                ]),
+                dict(vec![
+                    ("$classname", Value::String("NSArray".to_string())),
+                    (
+                        "$classes",
+                        Value::Array(vec![
+                            Value::String("NSArray".to_string()),
+                            Value::String("NSObject".to_string()),
+                        ]),
+                    ),
+                ]),
+                Value::String("Hello".to_string()),
+                Value::String("World".to_string()),
+            ]),
+        ),
+    ]);
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    archive.to_file_binary(&path).unwrap();
+    path
+}
+
+fn run(args: &[&str], plist_in: &PathBuf, file_out: &PathBuf) {
+    let status = Command::new(env!("CARGO_BIN_EXE_nskeyedarchiver_converter"))
+        .args(args)
+        .arg(plist_in)
+        .arg(file_out)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn objects_range_exports_raw_objects() {
+    let plist_in = write_archive("objects_range.plist");
+    let file_out = plist_in.with_extension("out.plist");
+    run(&["--objects-range", "1..4"], &plist_in, &file_out);
+
+    let exported = Value::from_file(&file_out).unwrap();
+    let exported = exported.as_array().unwrap();
+    assert_eq!(exported.len(), 3);
+    // References are exported as `CF$UID` dictionaries
+    let cf_uid = |index: u64| dict(vec![("CF$UID", Value::Integer(index.into()))]);
+    assert_eq!(
+        exported[0],
+        dict(vec![
+            ("$class", cf_uid(2)),
+            ("NS.objects", Value::Array(vec![cf_uid(3), cf_uid(4)])),
+        ])
+    );
+    assert_eq!(exported[2], Value::String("Hello".to_string()));
+
+    // Ranges past the end of `$objects` or including `$null` are rejected
+    for range in ["1..1000", "0..4"] {
+        let _ = std::fs::remove_file(&file_out);
+        let output = Command::new(env!("CARGO_BIN_EXE_nskeyedarchiver_converter"))
+            .args(["--objects-range", range])
+            .arg(&plist_in)
+            .arg(&file_out)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--objects-range"));
+        assert!(!file_out.exists());
+    }
+}
+
+#[test]
+fn plist_flag_writes_an_xml_plist() {
+    let plist_in = write_archive("plist_flag.plist");
+    let file_out = plist_in.with_extension("out.xml");
+    let _ = std::fs::remove_file(&file_out);
+    run(&["-p"], &plist_in, &file_out);
+
+    assert!(file_out.exists());
+    let exported = Value::from_file(&file_out).unwrap();
+    assert_eq!(
+        exported,
+        dict(vec![(
+            "root",
+            Value::Array(vec![
+                Value::String("Hello".to_string()),
+                Value::String("World".to_string()),
+            ])
+        )])
+    );
+    assert!(std::fs::read(&file_out).unwrap().starts_with(b"<?xml"));
+}
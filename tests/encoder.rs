@@ -0,0 +1,62 @@
+use nskeyedarchiver_converter::plist::{Dictionary, Uid, Value};
+use nskeyedarchiver_converter::Converter;
+
+fn dict(pairs: Vec<(&str, Value)>) -> Value {
+    let mut dict = Dictionary::new();
+    for (key, value) in pairs {
+        dict.insert(key.to_string(), value);
+    }
+    Value::Dictionary(dict)
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+#[test]
+fn modified_string_survives_reencoding() {
+    let uid = |index| Value::Uid(Uid::new(index));
+    let archive = dict(vec![
+        ("$version", Value::Integer(100000.into())),
+        ("$archiver", string("NSKeyedArchiver")),
+        ("$top", dict(vec![("root", uid(1))])),
+        (
+            "$objects",
+            Value::Array(vec![
+                string("$null"),
+                dict(vec![
+                    ("$class", uid(2)),
+                    ("NS.objects", Value::Array(vec![uid(3), uid(4)])),
+                ]),
+                dict(vec![
+                    ("$classname", string("NSArray")),
+                    (
+                        "$classes",
+                        Value::Array(vec![string("NSArray"), string("NSObject")]),
+                    ),
+                ]),
+                string("Hello"),
+                string("World"),
+            ]),
+        ),
+    ]);
+
+    let mut decoded = Converter::new(archive).unwrap().decode().unwrap();
+    let root = decoded
+        .as_dictionary_mut()
+        .and_then(|top| top.get_mut("root"))
+        .and_then(Value::as_array_mut)
+        .unwrap();
+    root[1] = string("Rust");
+
+    let encoded = Converter::to_encoder(decoded.clone()).encode().unwrap();
+    let redecoded = Converter::new(encoded).unwrap().decode().unwrap();
+    assert_eq!(redecoded, decoded);
+    assert_eq!(
+        redecoded,
+        dict(vec![(
+            "root",
+            Value::Array(vec![string("Hello"), string("Rust")])
+        )])
+    );
+}